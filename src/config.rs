@@ -1,8 +1,17 @@
+use crate::filter::AddressFilter;
+use crate::http::build_client;
+use crate::netlink::glob_match;
+use crate::source::json_lookup;
+use crate::template;
+use crate::web::{apply_subnet_id, parse_header, parse_mac, TemplateVars};
 use anyhow::Result;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::Ipv6Addr;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -10,6 +19,12 @@ pub struct Task {
     pub id: String,
     pub name: String,
     pub suffix: String,
+    /// How the interface identifier is obtained
+    #[serde(default)]
+    pub suffix_mode: SuffixMode,
+    /// MAC address of the host, used by `suffix_mode: eui64` and `neighbor`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
     pub webhook_url: String,
     pub webhook_method: String,
     pub webhook_body: Option<String>,
@@ -18,25 +33,752 @@ pub struct Task {
     pub enabled: bool,
     #[serde(default)]
     pub allow_api_trigger: bool,
+    /// Overrides the global prefix length for this task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_len: Option<u8>,
+    /// Length of the prefix delegated to us (e.g. 56), for carving downstream subnets out of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delegated_prefix_len: Option<u8>,
+    /// Subnet ID (hex, e.g. `0x10`) placed between the delegated prefix and `prefix_len`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet_id: Option<String>,
+    /// Interfaces (or glob patterns) whose prefix changes run this task. Empty means all interfaces.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<String>,
+    /// Which address changes run this task
+    #[serde(default)]
+    pub address_family: IpFamily,
+    /// What to do when the prefix this task was last run for disappears
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_withdraw: Option<WithdrawAction>,
+    /// Retry failed webhook requests. Without it a task makes a single attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Timeouts, proxy and TLS settings of the webhook requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpOptions>,
+    /// What a successful response looks like, for providers that report errors with HTTP 200
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub success: Option<SuccessCriteria>,
+    /// Requests sent before the webhook, e.g. to look up a record ID
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<WebhookStep>,
+    /// Update the record through a DNS provider's API instead of calling the webhook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuffixMode {
+    /// `suffix` is a literal interface identifier
+    #[default]
+    Static,
+    /// Interface identifier derived from `mac` (modified EUI-64, as used by SLAAC)
+    Eui64,
+    /// Address `mac` is using under the current prefix, discovered from the kernel neighbor table
+    Neighbor,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// IPv6 prefix changes, publishing the combined address (AAAA)
+    #[default]
+    Ipv6,
+    /// IPv4 address changes (A)
+    Ipv4,
+    /// Either change, with both `{{combined_ip}}` and `{{ipv4}}` available
+    Both,
+}
+
+impl IpFamily {
+    pub fn includes(&self, ipv4: bool) -> bool {
+        match self {
+            IpFamily::Ipv6 => !ipv4,
+            IpFamily::Ipv4 => ipv4,
+            IpFamily::Both => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum WithdrawAction {
+    /// Run the task's own webhook with this address instead of the withdrawn one
+    Fallback { address: String },
+    /// Send a separate request, e.g. a DELETE for the record
+    Webhook {
+        webhook_url: String,
+        webhook_method: String,
+        #[serde(default)]
+        webhook_body: Option<String>,
+        #[serde(default)]
+        webhook_headers: HashMap<String, String>,
+    },
+}
+
+/// How a task's webhook requests reach the provider
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpOptions {
+    /// Seconds to establish the connection, 10 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    /// Seconds for the whole request including the response body, 30 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM file with additional CA certificates to trust
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    /// Accept any server certificate, for LAN endpoints with self-signed certificates
+    pub insecure: bool,
+    /// PEM client certificate for mTLS. The key may be appended or given in `client_key`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+    /// Connect over IPv4 or IPv6 only. `both` (the default) lets the resolver decide.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_family: Option<IpFamily>,
+}
+
+/// DNS provider APIs a task can update directly
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    Cloudflare(CloudflareConfig),
+    /// RFC 2136 dynamic update, e.g. to BIND, Knot or PowerDNS
+    Rfc2136(Rfc2136Config),
+    Desec(DesecConfig),
+    Dynv6(Dynv6Config),
+    #[serde(rename = "powerdns")]
+    PowerDns(PowerDnsConfig),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CloudflareConfig {
+    /// API token with `Zone:Read` and `DNS:Edit`, e.g. `{{ env.PREFIXDDNS_CF_API_TOKEN }}`
+    pub api_token: String,
+    /// Record to keep up to date, e.g. `home.example.com`
+    pub fqdn: String,
+    /// TTL in seconds for a record that has to be created, 1 meaning automatic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// Whether a record that has to be created is proxied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxied: Option<bool>,
+    /// API endpoint, `https://api.cloudflare.com/client/v4` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DesecConfig {
+    /// API token, e.g. `{{ env.PREFIXDDNS_DESEC_TOKEN }}`
+    pub token: String,
+    /// Domain registered with deSEC, e.g. `example.dedyn.io`
+    pub zone: String,
+    /// Record to keep up to date, the domain itself or a name below it
+    pub name: String,
+    /// TTL in seconds, 3600 if unset (the smallest deSEC allows by default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// API endpoint, `https://desec.io/api/v1` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Dynv6Config {
+    /// HTTP token from the dynv6 account settings
+    pub token: String,
+    /// Zone, e.g. `example.dynv6.net`
+    pub zone: String,
+    /// Record to keep up to date, the zone itself or a name below it
+    pub name: String,
+    /// API endpoint, `https://dynv6.com/api/v2` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PowerDnsConfig {
+    /// Value of `api-key` in the server's configuration
+    pub api_key: String,
+    /// Zone, e.g. `example.com`
+    pub zone: String,
+    /// Record to keep up to date, the zone itself or a name below it
+    pub name: String,
+    /// TTL in seconds, 300 if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// Server ID in the API path, `localhost` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<String>,
+    /// Address of the built-in web server, `http://127.0.0.1:8081` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rfc2136Config {
+    /// Primary server of the zone, e.g. `192.0.2.53` or `ns1.example.com:5353`
+    pub server: String,
+    /// Zone to update, e.g. `example.com`
+    pub zone: String,
+    /// Record to keep up to date, e.g. `home.example.com`
+    pub name: String,
+    /// TTL in seconds of the added record
+    #[serde(default = "default_dns_ttl")]
+    pub ttl: u32,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tsig: Option<TsigKey>,
+    /// Conditions the server checks before applying the update
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prerequisites: Vec<Prerequisite>,
+}
+
+fn default_dns_ttl() -> u32 {
+    300
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    /// UDP, switching to TCP if the response is truncated
+    #[default]
+    Udp,
+    Tcp,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TsigKey {
+    /// Key name as configured on the server, e.g. `ddns-key`
+    pub name: String,
+    #[serde(default)]
+    pub algorithm: TsigAlgorithm,
+    /// Base64 secret, e.g. `{{ env.PREFIXDDNS_TSIG_SECRET }}`
+    pub secret: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TsigAlgorithm {
+    #[default]
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+    #[serde(rename = "hmac-sha512")]
+    HmacSha512,
+}
+
+/// RFC 2136 section 2.4 prerequisites. `rtype` is a record type such as `AAAA` or `TYPE65`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Prerequisite {
+    NameInUse { name: String },
+    NameNotInUse { name: String },
+    RrsetExists { name: String, rtype: String },
+    RrsetNotExists { name: String, rtype: String },
+}
+
+impl Prerequisite {
+    pub fn name(&self) -> &String {
+        match self {
+            Prerequisite::NameInUse { name }
+            | Prerequisite::NameNotInUse { name }
+            | Prerequisite::RrsetExists { name, .. }
+            | Prerequisite::RrsetNotExists { name, .. } => name,
+        }
+    }
+}
+
+impl ProviderConfig {
+    /// Fields rendered as templates before use
+    pub fn templates(&self) -> Vec<&String> {
+        match self {
+            ProviderConfig::Cloudflare(config) => vec![&config.api_token, &config.fqdn],
+            ProviderConfig::Rfc2136(config) => {
+                let mut templates = vec![&config.name];
+                templates.extend(config.tsig.as_ref().map(|key| &key.secret));
+                templates.extend(config.prerequisites.iter().map(Prerequisite::name));
+                templates
+            }
+            ProviderConfig::Desec(config) => vec![&config.token, &config.name],
+            ProviderConfig::Dynv6(config) => vec![&config.token, &config.name],
+            ProviderConfig::PowerDns(config) => vec![&config.api_key, &config.name],
+        }
+    }
+}
+
+/// A request in front of the task's webhook. Values captured from its response
+/// are available to the following steps and the webhook as template variables.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookStep {
+    /// Shown in the log when the step fails
+    #[serde(default)]
+    pub name: String,
+    /// Template expression; the step is skipped unless it is true, e.g. `not record_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    pub webhook_url: String,
+    pub webhook_method: String,
+    #[serde(default)]
+    pub webhook_body: Option<String>,
+    #[serde(default)]
+    pub webhook_headers: HashMap<String, String>,
+    /// Variables to set from the response, keyed by variable name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub captures: HashMap<String, Capture>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub success: Option<SuccessCriteria>,
+}
+
+/// Where a step finds a value in its response. Nothing found leaves the variable undefined.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capture {
+    /// Dotted path into a JSON body, e.g. `result.0.id`
+    JsonPath(String),
+    /// Regular expression; the first group that took part in the match, else the whole match
+    Regex(String),
+}
+
+impl Capture {
+    pub fn extract(&self, body: &str) -> Option<serde_json::Value> {
+        match self {
+            Capture::JsonPath(path) => {
+                let json: serde_json::Value = serde_json::from_str(body).ok()?;
+                json_lookup(&json, path)
+                    .filter(|value| !value.is_null())
+                    .cloned()
+            }
+            Capture::Regex(pattern) => {
+                let captures = Regex::new(pattern).ok()?.captures(body)?;
+                let found = captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .next()
+                    .or(captures.get(0))?;
+                Some(serde_json::Value::String(found.as_str().to_string()))
+            }
+        }
+    }
+}
+
+impl WebhookStep {
+    /// The task's own webhook, run after its steps
+    pub fn from_task(task: &Task) -> Self {
+        Self {
+            name: String::new(),
+            when: None,
+            webhook_url: task.webhook_url.clone(),
+            webhook_method: task.webhook_method.clone(),
+            webhook_body: task.webhook_body.clone(),
+            webhook_headers: task.webhook_headers.clone(),
+            captures: HashMap::new(),
+            success: task.success.clone(),
+        }
+    }
+}
+
+/// Checks a webhook response must pass to count as success. All configured checks apply.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuccessCriteria {
+    /// Accepted status codes. Empty means any 2xx.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub status: Vec<u16>,
+    /// Text the body must contain, e.g. `OK` for DuckDNS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_contains: Option<String>,
+    /// Regular expression the body must match, e.g. `^(good|nochg)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_regex: Option<String>,
+    /// Dotted path into a JSON body, e.g. `success` for Cloudflare
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_path: Option<String>,
+    /// Value expected at `json_path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_equals: Option<serde_json::Value>,
+}
+
+impl SuccessCriteria {
+    pub fn accepts_status(&self, status: u16) -> bool {
+        if self.status.is_empty() {
+            (200..300).contains(&status)
+        } else {
+            self.status.contains(&status)
+        }
+    }
+
+    /// Why a response with `status` and `body` does not count as success, if it does not
+    pub fn check(&self, status: u16, body: &str) -> Result<(), String> {
+        if !self.accepts_status(status) {
+            return Err(format!("status {} not accepted", status));
+        }
+        if let Some(needle) = &self.body_contains {
+            if !body.contains(needle.as_str()) {
+                return Err(format!("body does not contain {:?}", needle));
+            }
+        }
+        if let Some(pattern) = &self.body_regex {
+            let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+            if !regex.is_match(body) {
+                return Err(format!("body does not match /{}/", pattern));
+            }
+        }
+        if let Some(path) = &self.json_path {
+            let json: serde_json::Value =
+                serde_json::from_str(body).map_err(|_| "body is not JSON".to_string())?;
+            let found = json_lookup(&json, path);
+            match (&self.json_equals, found) {
+                (_, None) => return Err(format!("{} missing from body", path)),
+                (Some(expected), Some(found)) if found != expected => {
+                    return Err(format!("{} is {}, expected {}", path, found, expected));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often and how fast a failed webhook request is retried
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled for every further one
+    pub backoff_base: u64,
+    /// Upper bound of the delay in seconds
+    pub backoff_cap: u64,
+    /// Random spread applied to each delay, as a fraction (0.2 = ±20%)
+    pub jitter: f64,
+    /// Status codes worth retrying. Network errors and timeouts are always retried.
+    pub retry_on_status: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff_base: 5,
+            backoff_cap: 300,
+            jitter: 0.2,
+            retry_on_status: vec![408, 425, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before attempt `attempt + 1`, with `random` in 0..1 spreading it by `jitter`
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let base = self
+            .backoff_base
+            .saturating_mul(1u64 << exponent)
+            .min(self.backoff_cap) as f64;
+        let spread = 1.0 + self.jitter * (random * 2.0 - 1.0);
+        Duration::from_secs_f64((base * spread).max(0.0))
+    }
+}
+
+impl Task {
+    /// Prefix length used for this task, falling back to the global setting
+    pub fn effective_prefix_len(&self, default: u8) -> u8 {
+        self.prefix_len.unwrap_or(default)
+    }
+
+    /// Check the syntax of every template the task renders
+    pub fn validate_templates(&self) -> Result<()> {
+        let mut templates = vec![&self.webhook_url];
+        templates.extend(&self.webhook_body);
+        let mut headers: Vec<(&String, &String)> = self.webhook_headers.iter().collect();
+        if let Some(WithdrawAction::Webhook {
+            webhook_url,
+            webhook_body,
+            webhook_headers,
+            ..
+        }) = &self.on_withdraw
+        {
+            templates.push(webhook_url);
+            templates.extend(webhook_body);
+            headers.extend(webhook_headers);
+        }
+        if let Some(provider) = &self.provider {
+            templates.extend(provider.templates());
+        }
+        for step in &self.steps {
+            templates.push(&step.webhook_url);
+            templates.extend(&step.webhook_body);
+            headers.extend(&step.webhook_headers);
+        }
+        let sample = TemplateVars::sample();
+        templates
+            .into_iter()
+            .try_for_each(|source| template::validate(source, &sample))?;
+        for step in &self.steps {
+            if let Some(when) = &step.when {
+                template::validate_expression(when)
+                    .map_err(|e| anyhow::anyhow!("Step [{}]: {}", step.name, e))?;
+            }
+        }
+
+        for (name, value) in headers {
+            template::validate(name, &sample)?;
+            template::validate(value, &sample)?;
+            // Headers that only break for some values are caught when the request is built
+            if let (Ok(name), Ok(value)) = (
+                template::render(name, &sample),
+                template::render(value, &sample),
+            ) {
+                parse_header(&name, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Regular expressions in the success criteria and captures
+    pub fn regexes(&self) -> Vec<&String> {
+        let mut regexes: Vec<&String> = self
+            .success
+            .iter()
+            .chain(self.steps.iter().flat_map(|step| &step.success))
+            .filter_map(|success| success.body_regex.as_ref())
+            .collect();
+        for step in &self.steps {
+            regexes.extend(step.captures.values().filter_map(|capture| match capture {
+                Capture::Regex(pattern) => Some(pattern),
+                Capture::JsonPath(_) => None,
+            }));
+        }
+        regexes
+    }
+
+    /// Task sending the request of a `WithdrawAction::Webhook`. It keeps the task's template
+    /// variables and HTTP settings, but none of the steps, success criteria or provider that
+    /// publish an address.
+    pub fn withdraw_webhook(
+        &self,
+        webhook_url: String,
+        webhook_method: String,
+        webhook_body: Option<String>,
+        webhook_headers: HashMap<String, String>,
+    ) -> Task {
+        Task {
+            webhook_url,
+            webhook_method,
+            webhook_body,
+            webhook_headers,
+            success: None,
+            steps: Vec::new(),
+            provider: None,
+            ..self.clone()
+        }
+    }
+
+    /// Whether a prefix change on `interface` should run this task
+    pub fn watches_interface(&self, interface: &str) -> bool {
+        self.interfaces.is_empty()
+            || self
+                .interfaces
+                .iter()
+                .any(|pattern| glob_match(pattern, interface))
+    }
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn default_prefix_len() -> u8 {
+    64
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub log_limit: usize,
     #[serde(default)]
     pub run_on_startup: bool,
+    #[serde(default = "default_prefix_len")]
+    pub prefix_len: u8,
+    #[serde(default)]
+    pub address_filter: AddressFilter,
+    #[serde(default)]
+    pub dhcp_pd: DhcpPdConfig,
+    /// Where addresses are detected, in priority order. Empty means netlink only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceConfig>,
+    /// Seconds between polls of the sources
+    #[serde(default = "default_source_poll_interval")]
+    pub source_poll_interval: u64,
+    /// Seconds between comparisons of the kernel addresses with the known state, 0 to disable
+    #[serde(default = "default_reconcile_interval")]
+    pub reconcile_interval: u64,
+    /// Seconds a new prefix must stay unchanged before tasks run, 0 to run immediately
+    #[serde(default)]
+    pub stabilization_window: u64,
     pub tasks: Vec<Task>,
 }
 
+fn default_source_poll_interval() -> u64 {
+    300
+}
+
+fn default_reconcile_interval() -> u64 {
+    300
+}
+
+fn default_source_interface() -> String {
+    "public".to_string()
+}
+
+/// An address source. For each address family the first source reporting an address is used.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    /// Addresses of the monitored interfaces
+    Netlink,
+    /// A "what is my IP" endpoint returning the address as text, or as JSON with `json_path`
+    Http {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        json_path: Option<String>,
+        /// Interface name the address is reported on
+        #[serde(default = "default_source_interface")]
+        interface: String,
+        /// Timeouts, proxy and TLS settings of the request
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        http: Option<HttpOptions>,
+    },
+    /// A shell command printing addresses
+    Command {
+        command: String,
+        #[serde(default = "default_source_interface")]
+        interface: String,
+    },
+}
+
+/// Delegated prefixes read from DHCPv6 client state instead of interface addresses
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DhcpPdConfig {
+    /// Lease files (dhcpcd `*.lease6`) or environment dumps written by a client hook script
+    pub lease_files: Vec<String>,
+    /// Interface name to report the prefixes on, if the lease does not name one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// How the lease files are written
+    pub format: LeaseFormat,
+    /// Seconds between lease file reads
+    pub poll_interval: u64,
+    /// Accept hook invocations on `POST /api/dhcp-pd-hook`
+    pub hook_enabled: bool,
+}
+
+/// Format of a DHCP-PD lease file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    /// `KEY=VALUE` lines if every line is one, a DHCPv6 message otherwise
+    #[default]
+    Auto,
+    /// Environment dump written by a client hook script
+    Env,
+    /// Raw DHCPv6 message (dhcpcd `*.lease6`)
+    Dhcpv6,
+}
+
+impl Default for DhcpPdConfig {
+    fn default() -> Self {
+        Self {
+            lease_files: Vec::new(),
+            interface: None,
+            format: LeaseFormat::Auto,
+            poll_interval: 30,
+            hook_enabled: false,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Configured sources, defaulting to netlink alone
+    pub fn effective_sources(&self) -> Vec<SourceConfig> {
+        if self.sources.is_empty() {
+            vec![SourceConfig::Netlink]
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    /// Check values that serde cannot enforce on its own
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=128).contains(&self.prefix_len) {
+            anyhow::bail!("Invalid prefix length /{}", self.prefix_len);
+        }
+        self.address_filter.validate()?;
+        for task in &self.tasks {
+            if let Some(len) = task.prefix_len {
+                if !(1..=128).contains(&len) {
+                    anyhow::bail!("Task [{}]: invalid prefix length /{}", task.name, len);
+                }
+            }
+            if task.delegated_prefix_len.is_some() || task.subnet_id.is_some() {
+                apply_subnet_id(
+                    Ipv6Addr::UNSPECIFIED,
+                    task,
+                    task.effective_prefix_len(self.prefix_len),
+                )
+                .map_err(|e| anyhow::anyhow!("Task [{}]: {}", task.name, e))?;
+            }
+            if let Some(retry) = &task.retry {
+                if retry.max_attempts == 0 {
+                    anyhow::bail!(
+                        "Task [{}]: retry.max_attempts must be at least 1",
+                        task.name
+                    );
+                }
+                if !(0.0..=1.0).contains(&retry.jitter) {
+                    anyhow::bail!("Task [{}]: retry.jitter must be between 0 and 1", task.name);
+                }
+            }
+            task.validate_templates()
+                .map_err(|e| anyhow::anyhow!("Task [{}]: {}", task.name, e))?;
+            for pattern in task.regexes() {
+                Regex::new(pattern)
+                    .map_err(|e| anyhow::anyhow!("Task [{}]: invalid regex: {}", task.name, e))?;
+            }
+            if let Some(http) = &task.http {
+                build_client(http).map_err(|e| anyhow::anyhow!("Task [{}]: {}", task.name, e))?;
+            }
+            if task.suffix_mode != SuffixMode::Static {
+                parse_mac(task.mac.as_deref().unwrap_or_default())
+                    .map_err(|e| anyhow::anyhow!("Task [{}]: {}", task.name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             log_limit: 100,
             run_on_startup: false,
+            prefix_len: default_prefix_len(),
+            address_filter: AddressFilter::default(),
+            dhcp_pd: DhcpPdConfig::default(),
+            sources: Vec::new(),
+            source_poll_interval: default_source_poll_interval(),
+            reconcile_interval: default_reconcile_interval(),
+            stabilization_window: 0,
             tasks: Vec::new(),
         }
     }
@@ -91,4 +833,12 @@ impl ConfigManager {
     pub async fn get_run_on_startup(&self) -> bool {
         self.config.read().await.run_on_startup
     }
+
+    pub async fn get_prefix_len(&self) -> u8 {
+        self.config.read().await.prefix_len
+    }
+
+    pub async fn get_address_filter(&self) -> AddressFilter {
+        self.config.read().await.address_filter.clone()
+    }
 }
@@ -0,0 +1,346 @@
+use crate::netlink::{AddressEvent, AddressEventKind};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A prefix change waiting to settle
+struct Pending {
+    event: AddressEvent,
+    prefix: IpAddr,
+    /// Addresses currently holding the pending prefix
+    addresses: HashSet<IpAddr>,
+    deadline: Instant,
+    /// Removal of the settled prefix this change replaces, if it disappeared meanwhile
+    withdrawn: Option<AddressEvent>,
+}
+
+/// A change that has been stable for the whole window
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settled {
+    pub event: AddressEvent,
+    pub prefix: IpAddr,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Holds back prefix changes until they have been stable for the configured window,
+/// so a burst of changes (e.g. a PPPoE reconnect) runs the tasks once.
+/// The tracker keeps the settled prefix meanwhile, the pending one only lives here.
+#[derive(Default)]
+pub struct Stabilizer {
+    pending: HashMap<(String, bool), Pending>,
+    /// Addresses the kernel reported while Duplicate Address Detection was still running
+    tentative: HashSet<IpAddr>,
+}
+
+impl Stabilizer {
+    /// Queue `event` with its new `prefix` to run after `window`. Returns the change it superseded, if any.
+    pub fn defer(
+        &mut self,
+        event: AddressEvent,
+        prefix: IpAddr,
+        window: Duration,
+        now: Instant,
+    ) -> Option<AddressEvent> {
+        let key = (event.interface(), event.is_ipv4());
+        let previous = self.pending.remove(&key);
+        let pending = Pending {
+            addresses: HashSet::from([event.address]),
+            event,
+            prefix,
+            deadline: now + window,
+            withdrawn: previous.as_ref().and_then(|p| p.withdrawn.clone()),
+        };
+        self.pending.insert(key, pending);
+        previous.map(|previous| previous.event)
+    }
+
+    /// Add another address of the prefix waiting on the event's interface. Returns false if
+    /// no change with `prefix` is waiting there.
+    pub fn join(&mut self, event: &AddressEvent, prefix: IpAddr) -> bool {
+        match self.pending.get_mut(&(event.interface(), event.is_ipv4())) {
+            Some(pending) if pending.prefix == prefix => {
+                pending.addresses.insert(event.address);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop the change waiting on `interface`, e.g. because the settled prefix came back
+    pub fn cancel(&mut self, interface: &str, ipv4: bool) -> Option<AddressEvent> {
+        self.pending
+            .remove(&(interface.to_string(), ipv4))
+            .map(|pending| pending.event)
+    }
+
+    /// Forget a withdrawn address of a waiting prefix. Once the prefix lost its last address the
+    /// change is dropped and returned, along with the removal of the settled prefix it would
+    /// have replaced, if that is gone too.
+    pub fn withdraw(
+        &mut self,
+        event: &AddressEvent,
+    ) -> Option<(AddressEvent, Option<AddressEvent>)> {
+        let key = (event.interface(), event.is_ipv4());
+        let pending = self.pending.get_mut(&key)?;
+        if !pending.addresses.remove(&event.address) || !pending.addresses.is_empty() {
+            return None;
+        }
+        self.pending
+            .remove(&key)
+            .map(|pending| (pending.event, pending.withdrawn))
+    }
+
+    /// The settled prefix of the event's interface disappeared while a new one is waiting.
+    /// Returns false if nothing is waiting, so the removal has to be handled now.
+    pub fn settled_withdrawn(&mut self, event: &AddressEvent) -> bool {
+        match self.pending.get_mut(&(event.interface(), event.is_ipv4())) {
+            Some(pending) => {
+                pending.withdrawn.get_or_insert_with(|| event.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remember a tentative address until DAD completes, or forget it if it was removed
+    pub fn track_tentative(&mut self, event: &AddressEvent) {
+        if event.kind == AddressEventKind::Removed {
+            self.tentative.remove(&event.address);
+        } else {
+            self.tentative.insert(event.address);
+        }
+    }
+
+    /// Whether `address` was tentative until now, i.e. DAD just completed and it is ready for use
+    pub fn dad_completed(&mut self, address: IpAddr) -> bool {
+        self.tentative.remove(&address)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+
+    /// Remove and return the changes that have been stable long enough
+    pub fn take_due(&mut self, now: Instant) -> Vec<Settled> {
+        let due: Vec<(String, bool)> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .map(|pending| Settled {
+                event: pending.event,
+                prefix: pending.prefix,
+                addresses: pending.addresses.into_iter().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Sleep until `deadline`, or forever if there is none
+pub async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{address_prefix, PrefixTracker};
+    use std::str::FromStr;
+
+    fn event(interface: &str, address: &str) -> AddressEvent {
+        AddressEvent {
+            kind: AddressEventKind::Added,
+            address: IpAddr::from_str(address).unwrap(),
+            prefix_len: 64,
+            if_index: 2,
+            if_name: Some(interface.to_string()),
+            flags: 0,
+            valid_lft: None,
+            preferred_lft: None,
+        }
+    }
+
+    fn removed(interface: &str, address: &str) -> AddressEvent {
+        AddressEvent {
+            kind: AddressEventKind::Removed,
+            ..event(interface, address)
+        }
+    }
+
+    fn prefix(event: &AddressEvent) -> IpAddr {
+        address_prefix(event.address, 64)
+    }
+
+    fn defer(
+        stabilizer: &mut Stabilizer,
+        event: AddressEvent,
+        window: Duration,
+        now: Instant,
+    ) -> Option<AddressEvent> {
+        let prefix = prefix(&event);
+        stabilizer.defer(event, prefix, window, now)
+    }
+
+    fn events(due: Vec<Settled>) -> Vec<AddressEvent> {
+        due.into_iter().map(|settled| settled.event).collect()
+    }
+
+    #[test]
+    fn test_latest_change_wins_after_window() {
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut stabilizer = Stabilizer::default();
+
+        assert!(defer(
+            &mut stabilizer,
+            event("ppp0", "2001:db8:1::1"),
+            window,
+            start
+        )
+        .is_none());
+        let superseded = defer(
+            &mut stabilizer,
+            event("ppp0", "2001:db8:2::1"),
+            window,
+            start + Duration::from_secs(3),
+        );
+        assert_eq!(superseded, Some(event("ppp0", "2001:db8:1::1")));
+        defer(
+            &mut stabilizer,
+            event("eth1", "2001:db8:9::1"),
+            window,
+            start,
+        );
+
+        // The superseding change restarted the window for ppp0
+        let due = stabilizer.take_due(start + Duration::from_secs(10));
+        assert_eq!(events(due), vec![event("eth1", "2001:db8:9::1")]);
+        assert_eq!(
+            stabilizer.next_deadline(),
+            Some(start + Duration::from_secs(13))
+        );
+        let due = stabilizer.take_due(start + Duration::from_secs(13));
+        assert_eq!(events(due), vec![event("ppp0", "2001:db8:2::1")]);
+        assert_eq!(stabilizer.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_dad_completion_releases_change() {
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut stabilizer = Stabilizer::default();
+        let ready = event("ppp0", "2001:db8:2::1");
+
+        defer(
+            &mut stabilizer,
+            event("ppp0", "2001:db8:1::1"),
+            window,
+            start,
+        );
+        stabilizer.track_tentative(&ready);
+        assert!(stabilizer.take_due(start).is_empty());
+
+        // The address lost its tentative flag: release it without waiting out the window
+        let now = start + Duration::from_secs(1);
+        assert!(stabilizer.dad_completed(ready.address));
+        assert!(!stabilizer.dad_completed(ready.address));
+        let superseded = defer(&mut stabilizer, ready.clone(), Duration::ZERO, now);
+        assert_eq!(superseded, Some(event("ppp0", "2001:db8:1::1")));
+        assert_eq!(stabilizer.next_deadline(), Some(now));
+        assert_eq!(events(stabilizer.take_due(now)), vec![ready]);
+
+        // DAD failed and the address was removed
+        let mut failed = event("ppp0", "2001:db8:3::1");
+        stabilizer.track_tentative(&failed);
+        failed.kind = AddressEventKind::Removed;
+        stabilizer.track_tentative(&failed);
+        assert!(!stabilizer.dad_completed(failed.address));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut stabilizer = Stabilizer::default();
+        defer(
+            &mut stabilizer,
+            event("ppp0", "2001:db8:1::1"),
+            Duration::from_secs(10),
+            Instant::now(),
+        );
+        assert!(stabilizer.cancel("ppp0", true).is_none());
+        assert!(stabilizer.cancel("ppp0", false).is_some());
+        assert_eq!(stabilizer.next_deadline(), None);
+    }
+
+    /// Published prefix A, then B waiting to settle, as the main loop records them
+    fn published_then_pending() -> (PrefixTracker, Stabilizer) {
+        let mut tracker = PrefixTracker::default();
+        let mut stabilizer = Stabilizer::default();
+        let a = event("ppp0", "2001:db8:1::1");
+        assert!(tracker.observe("ppp0", prefix(&a), a.address, 2));
+
+        let b = event("ppp0", "2001:db8:2::1");
+        assert!(!stabilizer.join(&b, prefix(&b)));
+        defer(&mut stabilizer, b, Duration::from_secs(10), Instant::now());
+        let b2 = event("ppp0", "2001:db8:2::2");
+        assert!(stabilizer.join(&b2, prefix(&b2)));
+        (tracker, stabilizer)
+    }
+
+    #[test]
+    fn test_settled_prefix_withdrawn_while_new_one_waits() {
+        // A goes first: B is about to replace it, the withdrawal waits with it
+        let (mut tracker, mut stabilizer) = published_then_pending();
+        let a = removed("ppp0", "2001:db8:1::1");
+        assert_eq!(tracker.withdraw("ppp0", a.address), Some(prefix(&a)));
+        assert!(stabilizer.settled_withdrawn(&a));
+
+        // B never settles: the withdrawal of A is due after all
+        assert!(tracker
+            .withdraw("ppp0", removed("ppp0", "2001:db8:2::1").address)
+            .is_none());
+        assert!(stabilizer
+            .withdraw(&removed("ppp0", "2001:db8:2::1"))
+            .is_none());
+        let (pending, withdrawn) = stabilizer
+            .withdraw(&removed("ppp0", "2001:db8:2::2"))
+            .unwrap();
+        assert_eq!(pending, event("ppp0", "2001:db8:2::1"));
+        assert_eq!(withdrawn, Some(a));
+        assert_eq!(stabilizer.next_deadline(), None);
+
+        // B goes first: A is still published and tracked, its removal is handled as usual
+        let (mut tracker, mut stabilizer) = published_then_pending();
+        stabilizer.withdraw(&removed("ppp0", "2001:db8:2::1"));
+        let (_, withdrawn) = stabilizer
+            .withdraw(&removed("ppp0", "2001:db8:2::2"))
+            .unwrap();
+        assert_eq!(withdrawn, None);
+        let a = removed("ppp0", "2001:db8:1::1");
+        assert_eq!(tracker.withdraw("ppp0", a.address), Some(prefix(&a)));
+        assert!(!stabilizer.settled_withdrawn(&a));
+    }
+
+    #[test]
+    fn test_settled_change_carries_its_addresses() {
+        let (_, mut stabilizer) = published_then_pending();
+        let due = stabilizer.take_due(Instant::now() + Duration::from_secs(10));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].prefix, IpAddr::from_str("2001:db8:2::").unwrap());
+        let mut addresses = due[0].addresses.clone();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec![
+                IpAddr::from_str("2001:db8:2::1").unwrap(),
+                IpAddr::from_str("2001:db8:2::2").unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use crate::config::{ConfigManager, DhcpPdConfig, LeaseFormat};
+use crate::netlink::{AddressEvent, AddressEventKind};
+use chrono::Local;
+use colored::Colorize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Interface name used when neither the lease nor the config names one
+pub const DEFAULT_PD_INTERFACE: &str = "dhcp-pd";
+
+/// Interface name to report delegated prefixes on, e.g. `dhcp-pd:eth0`. Kept apart from
+/// kernel interface names so a lease for `eth0` never replaces the prefix tracked on `eth0` itself.
+pub fn pd_interface(name: Option<String>) -> String {
+    match name {
+        Some(name) => format!("{}:{}", DEFAULT_PD_INTERFACE, name),
+        None => DEFAULT_PD_INTERFACE.to_string(),
+    }
+}
+
+// DHCPv6 option codes (RFC 8415)
+const OPTION_IA_PD: u16 = 25;
+const OPTION_IAPREFIX: u16 = 26;
+
+/// A prefix delegated to us by the upstream DHCPv6 server
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelegatedPrefix {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+    pub preferred_lft: Option<u32>,
+    pub valid_lft: Option<u32>,
+}
+
+/// Delegated prefixes read from a lease, plus the interface it names (if any)
+#[derive(Debug, Default, PartialEq)]
+pub struct Lease {
+    pub interface: Option<String>,
+    pub prefixes: Vec<DelegatedPrefix>,
+}
+
+/// Reads delegated prefixes from DHCPv6 client state and reports them as address events,
+/// for routers that never put an address from the delegated prefix on the WAN interface
+pub struct DhcpPdSource {
+    tx: broadcast::Sender<AddressEvent>,
+    /// Prefixes last reported by each lease file or hook, with the interface they were reported on
+    leases: Mutex<HashMap<String, (String, Vec<DelegatedPrefix>)>>,
+}
+
+impl DhcpPdSource {
+    pub fn new(tx: broadcast::Sender<AddressEvent>) -> Self {
+        Self {
+            tx,
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Poll the configured lease files for as long as the program runs
+    pub async fn run(&self, config_manager: ConfigManager) {
+        let mut announced = false;
+        loop {
+            let config = config_manager.config.read().await.dhcp_pd.clone();
+            if !config.lease_files.is_empty() && !announced {
+                println!(
+                    "{} {} DHCPv6-PD source started, watching {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    "[Init]".green(),
+                    config.lease_files.join(", ")
+                );
+                announced = true;
+            }
+            for path in &config.lease_files {
+                self.poll_file(path, &config);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(config.poll_interval.max(1))).await;
+        }
+    }
+
+    fn poll_file(&self, path: &str, config: &DhcpPdConfig) {
+        let lease = match std::fs::read(path) {
+            Ok(bytes) => match parse_lease(&bytes, config.format) {
+                Ok(lease) => lease,
+                Err(e) => {
+                    eprintln!(
+                        "{} {} Failed to parse lease {}: {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        "[Error]".red(),
+                        path,
+                        e
+                    );
+                    return;
+                }
+            },
+            // No lease (yet, or any more) means no delegated prefix
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Lease::default(),
+            Err(e) => {
+                eprintln!(
+                    "{} {} Failed to read lease {}: {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    "[Error]".red(),
+                    path,
+                    e
+                );
+                return;
+            }
+        };
+
+        // dhcpcd names its lease files after the interface, e.g. eth0.lease6
+        let interface = pd_interface(
+            lease
+                .interface
+                .clone()
+                .or_else(|| config.interface.clone())
+                .or_else(|| {
+                    Path::new(path)
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().into_owned())
+                }),
+        );
+        self.update(&format!("file:{}", path), &interface, lease.prefixes);
+    }
+
+    /// Record the prefixes now held by `origin` and announce what changed. Returns the number of events sent.
+    pub fn update(&self, origin: &str, interface: &str, prefixes: Vec<DelegatedPrefix>) -> usize {
+        let mut leases = self.leases.lock().unwrap();
+        let (old_interface, old_prefixes) = leases
+            .remove(origin)
+            .unwrap_or_else(|| (interface.to_string(), Vec::new()));
+
+        let mut events = Vec::new();
+        for old in &old_prefixes {
+            let kept = old_interface == interface
+                && prefixes
+                    .iter()
+                    .any(|p| p.prefix == old.prefix && p.prefix_len == old.prefix_len);
+            if !kept {
+                events.push(prefix_event(old, &old_interface, AddressEventKind::Removed));
+            }
+        }
+        for new in &prefixes {
+            let known = old_interface == interface
+                && old_prefixes
+                    .iter()
+                    .any(|p| p.prefix == new.prefix && p.prefix_len == new.prefix_len);
+            if !known {
+                events.push(prefix_event(new, interface, AddressEventKind::Added));
+            }
+        }
+
+        if !prefixes.is_empty() {
+            leases.insert(origin.to_string(), (interface.to_string(), prefixes));
+        }
+
+        let count = events.len();
+        for event in events {
+            let _ = self.tx.send(event);
+        }
+        count
+    }
+}
+
+fn prefix_event(prefix: &DelegatedPrefix, interface: &str, kind: AddressEventKind) -> AddressEvent {
+    AddressEvent {
+        kind,
+        address: IpAddr::V6(prefix.prefix),
+        prefix_len: prefix.prefix_len,
+        // Not a kernel interface, so reconciling with the kernel's addresses leaves it alone
+        if_index: 0,
+        if_name: Some(interface.to_string()),
+        flags: 0,
+        valid_lft: prefix.valid_lft,
+        preferred_lft: prefix.preferred_lft,
+    }
+}
+
+/// Parse either an environment dump from a client hook or a binary DHCPv6 lease (dhcpcd `*.lease6`)
+pub fn parse_lease(bytes: &[u8], format: LeaseFormat) -> anyhow::Result<Lease> {
+    let format = match format {
+        LeaseFormat::Auto if check_env_lease(bytes).is_ok() => LeaseFormat::Env,
+        LeaseFormat::Auto => LeaseFormat::Dhcpv6,
+        format => format,
+    };
+    if format == LeaseFormat::Env {
+        check_env_lease(bytes)?;
+        // Checked to be UTF-8 above
+        return Ok(parse_env_lease(std::str::from_utf8(bytes)?));
+    }
+    Ok(Lease {
+        interface: None,
+        prefixes: parse_dhcpv6_lease(bytes)?,
+    })
+}
+
+/// Ensure every non-empty line is a shell `KEY=VALUE` assignment, as `env` and `set` print them
+fn check_env_lease(bytes: &[u8]) -> anyhow::Result<()> {
+    let text =
+        std::str::from_utf8(bytes).map_err(|e| anyhow::anyhow!("Not a text lease: {}", e))?;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let key = line.split_once('=').map(|(key, _)| key);
+        let is_name = key.is_some_and(|key| {
+            !key.is_empty()
+                && !key.starts_with(|c: char| c.is_ascii_digit())
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+        if !is_name {
+            anyhow::bail!("Line {} is not a KEY=VALUE assignment", number + 1);
+        }
+    }
+    Ok(())
+}
+
+/// Parse `KEY=VALUE` lines as passed to odhcp6c and dhcpcd hook scripts.
+///
+/// - odhcp6c: `PREFIXES="2001:db8:ab00::/56,3600,7200 ..."` (prefix/len,preferred,valid[,extra])
+/// - dhcpcd: `new_dhcp6_ia_pd1_prefix1=2001:db8:ab00::` with `_length`, `_pltime` and `_vltime`
+pub fn parse_env_lease(text: &str) -> Lease {
+    let mut vars = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            vars.insert(key.trim().to_string(), value.to_string());
+        }
+    }
+
+    let mut prefixes = Vec::new();
+
+    if let Some(list) = vars.get("PREFIXES") {
+        for entry in list.split_whitespace() {
+            let mut fields = entry.split(',');
+            let Some((prefix, len)) = fields.next().and_then(|p| p.split_once('/')) else {
+                continue;
+            };
+            let (Ok(prefix), Ok(prefix_len)) = (prefix.parse(), len.parse()) else {
+                continue;
+            };
+            prefixes.push(DelegatedPrefix {
+                prefix,
+                prefix_len,
+                preferred_lft: fields.next().and_then(|v| v.parse().ok()),
+                valid_lft: fields.next().and_then(|v| v.parse().ok()),
+            });
+        }
+    }
+
+    let mut keys: Vec<&String> = vars
+        .keys()
+        .filter(|k| k.starts_with("new_dhcp6_ia_pd") && k.contains("_prefix"))
+        .collect();
+    keys.sort();
+    for key in keys {
+        if key.ends_with("_length") || key.ends_with("_pltime") || key.ends_with("_vltime") {
+            continue;
+        }
+        let Ok(prefix) = vars[key].parse() else {
+            continue;
+        };
+        let Some(prefix_len) = vars
+            .get(&format!("{}_length", key))
+            .and_then(|v| v.parse().ok())
+        else {
+            continue;
+        };
+        prefixes.push(DelegatedPrefix {
+            prefix,
+            prefix_len,
+            preferred_lft: vars
+                .get(&format!("{}_pltime", key))
+                .and_then(|v| v.parse().ok()),
+            valid_lft: vars
+                .get(&format!("{}_vltime", key))
+                .and_then(|v| v.parse().ok()),
+        });
+    }
+
+    Lease {
+        interface: vars
+            .get("interface")
+            .or_else(|| vars.get("INTERFACE"))
+            .filter(|v| !v.is_empty())
+            .cloned(),
+        prefixes,
+    }
+}
+
+/// Parse the IA_PD prefixes of a raw DHCPv6 message (msg-type, transaction-id, options)
+pub fn parse_dhcpv6_lease(bytes: &[u8]) -> anyhow::Result<Vec<DelegatedPrefix>> {
+    if bytes.len() < 4 {
+        anyhow::bail!("Lease too short");
+    }
+    let mut prefixes = Vec::new();
+    for (code, data) in dhcpv6_options(&bytes[4..])? {
+        // IAID, T1 and T2 precede the IA_PD sub-options
+        if code != OPTION_IA_PD || data.len() < 12 {
+            continue;
+        }
+        for (code, data) in dhcpv6_options(&data[12..])? {
+            if code != OPTION_IAPREFIX || data.len() < 25 {
+                continue;
+            }
+            let octets: [u8; 16] = data[9..25].try_into()?;
+            prefixes.push(DelegatedPrefix {
+                prefix: Ipv6Addr::from(octets),
+                prefix_len: data[8],
+                preferred_lft: Some(u32::from_be_bytes(data[0..4].try_into()?)),
+                valid_lft: Some(u32::from_be_bytes(data[4..8].try_into()?)),
+            });
+        }
+    }
+    Ok(prefixes)
+}
+
+fn dhcpv6_options(mut bytes: &[u8]) -> anyhow::Result<Vec<(u16, &[u8])>> {
+    let mut options = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            anyhow::bail!("Truncated DHCPv6 option");
+        }
+        let code = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if bytes.len() < 4 + len {
+            anyhow::bail!("Truncated DHCPv6 option {}", code);
+        }
+        options.push((code, &bytes[4..4 + len]));
+        bytes = &bytes[4 + len..];
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn delegated(prefix: &str, len: u8, preferred: u32, valid: u32) -> DelegatedPrefix {
+        DelegatedPrefix {
+            prefix: Ipv6Addr::from_str(prefix).unwrap(),
+            prefix_len: len,
+            preferred_lft: Some(preferred),
+            valid_lft: Some(valid),
+        }
+    }
+
+    #[test]
+    fn test_parse_odhcp6c_env() {
+        let lease = parse_env_lease(
+            "INTERFACE=wan\nPREFIXES='2001:db8:ab00::/56,3600,7200 2001:db8:cd00::/60,1800,3600,class=1'\nRA_ADDRESSES=",
+        );
+        assert_eq!(lease.interface.as_deref(), Some("wan"));
+        assert_eq!(
+            lease.prefixes,
+            vec![
+                delegated("2001:db8:ab00::", 56, 3600, 7200),
+                delegated("2001:db8:cd00::", 60, 1800, 3600),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dhcpcd_env() {
+        let lease = parse_env_lease(
+            "export interface=\"eth0\"\nexport reason=\"BOUND6\"\n\
+             export new_dhcp6_ia_pd1_prefix1=\"2001:db8:ab00::\"\n\
+             export new_dhcp6_ia_pd1_prefix1_length=\"56\"\n\
+             export new_dhcp6_ia_pd1_prefix1_pltime=\"3600\"\n\
+             export new_dhcp6_ia_pd1_prefix1_vltime=\"7200\"\n\
+             export old_dhcp6_ia_pd1_prefix1=\"2001:db8:9900::\"",
+        );
+        assert_eq!(lease.interface.as_deref(), Some("eth0"));
+        assert_eq!(
+            lease.prefixes,
+            vec![delegated("2001:db8:ab00::", 56, 3600, 7200)]
+        );
+    }
+
+    /// Reply with an IA_PD holding one IAPREFIX
+    fn dhcpv6_reply(transaction_id: [u8; 3], prefix: &str) -> Vec<u8> {
+        let mut iaprefix = Vec::new();
+        iaprefix.extend_from_slice(&3600u32.to_be_bytes());
+        iaprefix.extend_from_slice(&7200u32.to_be_bytes());
+        iaprefix.push(56);
+        iaprefix.extend_from_slice(&Ipv6Addr::from_str(prefix).unwrap().octets());
+
+        let mut ia_pd = vec![0, 0, 0, 1, 0, 0, 0x0e, 0x10, 0, 0, 0x15, 0x18];
+        ia_pd.extend_from_slice(&OPTION_IAPREFIX.to_be_bytes());
+        ia_pd.extend_from_slice(&(iaprefix.len() as u16).to_be_bytes());
+        ia_pd.extend_from_slice(&iaprefix);
+
+        let mut msg = vec![7];
+        msg.extend_from_slice(&transaction_id);
+        // Client ID, skipped
+        msg.extend_from_slice(&[0, 1, 0, 2, 0x2b, 0x3c]);
+        msg.extend_from_slice(&OPTION_IA_PD.to_be_bytes());
+        msg.extend_from_slice(&(ia_pd.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&ia_pd);
+        msg
+    }
+
+    #[test]
+    fn test_parse_dhcpv6_lease() {
+        let msg = dhcpv6_reply([0x12, 0x34, 0x56], "2001:db8:ab00::");
+        assert_eq!(
+            parse_lease(&msg, LeaseFormat::Auto).unwrap().prefixes,
+            vec![delegated("2001:db8:ab00::", 56, 3600, 7200)]
+        );
+        assert!(parse_dhcpv6_lease(&msg[..msg.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_lease_format() {
+        // Valid UTF-8 holding a '=', which still is no environment dump
+        let msg = dhcpv6_reply([b'=', b'=', b'='], "2001:d3d::");
+        assert!(std::str::from_utf8(&msg).is_ok());
+        let expected = vec![delegated("2001:d3d::", 56, 3600, 7200)];
+        assert_eq!(
+            parse_lease(&msg, LeaseFormat::Auto).unwrap().prefixes,
+            expected
+        );
+        assert_eq!(
+            parse_lease(&msg, LeaseFormat::Dhcpv6).unwrap().prefixes,
+            expected
+        );
+        let err = parse_lease(&msg, LeaseFormat::Env).unwrap_err();
+        assert!(err.to_string().contains("KEY=VALUE"), "{}", err);
+
+        let env = b"# written by the hook\nexport INTERFACE=wan\nPREFIXES='2001:db8:ab00::/56,3600,7200'\n";
+        assert_eq!(
+            parse_lease(env, LeaseFormat::Auto).unwrap().prefixes,
+            vec![delegated("2001:db8:ab00::", 56, 3600, 7200)]
+        );
+        assert!(parse_lease(env, LeaseFormat::Dhcpv6).is_err());
+    }
+
+    #[test]
+    fn test_update_announces_changes_only() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let source = DhcpPdSource::new(tx);
+        let first = delegated("2001:db8:ab00::", 56, 3600, 7200);
+        let second = delegated("2001:db8:cd00::", 56, 3600, 7200);
+
+        assert_eq!(source.update("hook", "wan", vec![first.clone()]), 1);
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.kind, AddressEventKind::Added);
+        assert_eq!(event.interface(), "wan");
+
+        // Renewals are not news
+        assert_eq!(source.update("hook", "wan", vec![first.clone()]), 0);
+
+        assert_eq!(source.update("hook", "wan", vec![second]), 2);
+        assert_eq!(rx.try_recv().unwrap().kind, AddressEventKind::Removed);
+        assert_eq!(rx.try_recv().unwrap().kind, AddressEventKind::Added);
+
+        assert_eq!(source.update("hook", "wan", Vec::new()), 1);
+        assert_eq!(rx.try_recv().unwrap().kind, AddressEventKind::Removed);
+    }
+
+    #[test]
+    fn test_lease_interface_is_namespaced() {
+        let dir = std::env::temp_dir().join(format!("prefixddns-dhcp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("eth0.lease6");
+        std::fs::write(&path, "PREFIXES='2001:db8:ab00::/56,3600,7200'").unwrap();
+
+        let (tx, mut rx) = broadcast::channel(16);
+        let source = DhcpPdSource::new(tx);
+        source.poll_file(&path.to_string_lossy(), &DhcpPdConfig::default());
+        let event = rx.try_recv().unwrap();
+        // Never the kernel's eth0, whose prefix netlink tracks separately
+        assert_eq!(event.interface(), "dhcp-pd:eth0");
+        assert_eq!(pd_interface(None), "dhcp-pd");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
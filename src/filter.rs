@@ -0,0 +1,239 @@
+use crate::netlink::{AddressEvent, IFA_F_MANAGETEMPADDR, IFA_F_STABLE_PRIVACY, IFA_F_TEMPORARY};
+use crate::web::{ipv4_mask, prefix_mask};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Decides which kernel addresses are allowed to drive prefix updates
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddressFilter {
+    /// If non-empty, only addresses inside one of these CIDRs are used
+    pub include: Vec<String>,
+    /// Addresses inside any of these CIDRs are ignored
+    pub exclude: Vec<String>,
+    /// Ignore RFC 4941 temporary (privacy) addresses
+    pub skip_temporary: bool,
+    /// Ignore unique local addresses (fc00::/7)
+    pub skip_ula: bool,
+    /// Only use addresses flagged managetempaddr or stable-privacy
+    pub require_managed_or_stable: bool,
+    /// Ignore addresses whose preferred lifetime is below this many seconds
+    pub min_preferred_lft: u32,
+}
+
+impl AddressFilter {
+    pub fn validate(&self) -> Result<()> {
+        for cidr in self.include.iter().chain(&self.exclude) {
+            Cidr::from_str(cidr)?;
+        }
+        Ok(())
+    }
+
+    /// Returns why the address is rejected, or None if it may be used
+    pub fn reject_reason(&self, event: &AddressEvent) -> Option<&'static str> {
+        let addr = event.address;
+        // Temporary, ULA and stable-privacy only exist for IPv6
+        if let IpAddr::V6(v6) = addr {
+            if self.skip_ula && (v6.segments()[0] & 0xfe00) == 0xfc00 {
+                return Some("unique local address");
+            }
+        }
+        // Flags and lifetimes are only meaningful on addresses the kernel reported
+        if event.is_kernel() {
+            if let Some(reason) = self.reject_kernel(event) {
+                return Some(reason);
+            }
+        }
+        // The include list only restricts the families it mentions
+        let include = Self::parse_family(&self.include, addr);
+        if !include.is_empty() && !include.iter().any(|cidr| cidr.contains(addr)) {
+            return Some("not in include list");
+        }
+        if Self::parse_family(&self.exclude, addr)
+            .iter()
+            .any(|cidr| cidr.contains(addr))
+        {
+            return Some("in exclude list");
+        }
+        None
+    }
+
+    fn reject_kernel(&self, event: &AddressEvent) -> Option<&'static str> {
+        if event.address.is_ipv6() {
+            if self.skip_temporary && event.has_flag(IFA_F_TEMPORARY) {
+                return Some("temporary address");
+            }
+            if self.require_managed_or_stable
+                && !event.has_flag(IFA_F_MANAGETEMPADDR | IFA_F_STABLE_PRIVACY)
+            {
+                return Some("neither managed nor stable-privacy");
+            }
+        }
+        match event.preferred_lft {
+            Some(preferred) if preferred < self.min_preferred_lft => {
+                Some("preferred lifetime too short")
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_family(cidrs: &[String], addr: IpAddr) -> Vec<Cidr> {
+        cidrs
+            .iter()
+            .filter_map(|c| Cidr::from_str(c).ok())
+            .filter(|cidr| cidr.network.is_ipv4() == addr.is_ipv4())
+            .collect()
+    }
+}
+
+/// A network such as `2001:db8::/32` or `192.0.2.0/24`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = prefix_mask(self.prefix_len);
+                u128::from(addr) & mask == u128::from(network) & mask
+            }
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = ipv4_mask(self.prefix_len);
+                u32::from(addr) & mask == u32::from(network) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (IpAddr::from_str(addr)?, Some(len.parse::<u8>()?)),
+            None => (IpAddr::from_str(s.trim())?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = len.unwrap_or(max_len);
+        if len > max_len {
+            anyhow::bail!("Invalid prefix length in {}", s);
+        }
+        Ok(Self {
+            network: addr,
+            prefix_len: len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netlink::AddressEventKind;
+
+    fn event(addr: &str, flags: u32, preferred_lft: Option<u32>) -> AddressEvent {
+        AddressEvent {
+            kind: AddressEventKind::Added,
+            address: IpAddr::from_str(addr).unwrap(),
+            prefix_len: 64,
+            if_index: 2,
+            if_name: Some("eth0".to_string()),
+            flags,
+            valid_lft: preferred_lft,
+            preferred_lft,
+        }
+    }
+
+    #[test]
+    fn test_default_filter_allows_everything() {
+        let filter = AddressFilter::default();
+        assert_eq!(
+            filter.reject_reason(&event("fd00::1", IFA_F_TEMPORARY, Some(0))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_address_class_filters() {
+        let filter = AddressFilter {
+            skip_temporary: true,
+            skip_ula: true,
+            require_managed_or_stable: true,
+            min_preferred_lft: 600,
+            ..Default::default()
+        };
+        let stable = event("2001:db8::1", IFA_F_STABLE_PRIVACY, Some(3600));
+        assert_eq!(filter.reject_reason(&stable), None);
+        assert!(filter
+            .reject_reason(&event("2001:db8::2", IFA_F_TEMPORARY, Some(3600)))
+            .is_some());
+        assert!(filter
+            .reject_reason(&event("fd12::1", IFA_F_STABLE_PRIVACY, Some(3600)))
+            .is_some());
+        assert!(filter
+            .reject_reason(&event("2001:db8::3", 0, Some(3600)))
+            .is_some());
+        assert!(filter
+            .reject_reason(&event("2001:db8::4", IFA_F_MANAGETEMPADDR, Some(60)))
+            .is_some());
+    }
+
+    #[test]
+    fn test_delegated_prefix_skips_kernel_checks() {
+        let filter = AddressFilter {
+            skip_temporary: true,
+            require_managed_or_stable: true,
+            min_preferred_lft: 600,
+            exclude: vec!["2001:db8:bad::/48".to_string()],
+            ..Default::default()
+        };
+        // A DHCP-PD prefix carries no flags and may be close to its renewal
+        let delegated = |addr: &str| AddressEvent {
+            if_index: 0,
+            if_name: Some("dhcp-pd:wan0".to_string()),
+            ..event(addr, 0, Some(300))
+        };
+        assert_eq!(filter.reject_reason(&delegated("2001:db8:1::")), None);
+        assert_eq!(
+            filter.reject_reason(&delegated("2001:db8:bad::")),
+            Some("in exclude list")
+        );
+        assert!(filter
+            .reject_reason(&event("2001:db8:1::1", 0, Some(300)))
+            .is_some());
+    }
+
+    #[test]
+    fn test_cidr_lists() {
+        let filter = AddressFilter {
+            include: vec!["2000::/3".to_string()],
+            exclude: vec!["2001:db8:bad::/48".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(filter.reject_reason(&event("2001:db8:1::1", 0, None)), None);
+        assert!(filter
+            .reject_reason(&event("2001:db8:bad::1", 0, None))
+            .is_some());
+        assert!(filter.reject_reason(&event("fd00::1", 0, None)).is_some());
+        // No IPv4 entries in the include list, so IPv4 is unrestricted
+        assert_eq!(filter.reject_reason(&event("203.0.113.7", 0, None)), None);
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        let cidr = Cidr::from_str("fc00::/7").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("fdab::1").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("fe80::1").unwrap()));
+        assert!(Cidr::from_str("fc00::/129").is_err());
+        let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("10.1.2.3").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("11.0.0.1").unwrap()));
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("nonsense/7").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::{HttpOptions, IpFamily};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Webhook clients shared by all tasks, one per distinct set of connection settings.
+/// Reusing them keeps connections and TLS sessions alive between runs.
+#[derive(Clone, Default)]
+pub struct HttpClients {
+    clients: Arc<Mutex<HashMap<HttpOptions, reqwest::Client>>>,
+}
+
+impl HttpClients {
+    /// Client for `options`, built on first use
+    pub fn get(&self, options: &HttpOptions) -> Result<reqwest::Client> {
+        // The total timeout is set per request, so it does not need a client of its own
+        let key = HttpOptions {
+            timeout: None,
+            ..options.clone()
+        };
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = build_client(&key)?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Drop all clients, e.g. after the config changed and certificate files may have been replaced
+    pub fn clear(&self) {
+        self.clients.lock().unwrap().clear();
+    }
+}
+
+/// Total timeout of a request made with `options`
+pub fn request_timeout(options: &HttpOptions) -> Duration {
+    Duration::from_secs(options.timeout.unwrap_or(DEFAULT_TIMEOUT))
+}
+
+pub fn build_client(options: &HttpOptions) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("PrefixDDNS/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(Duration::from_secs(
+            options.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ))
+        .danger_accept_invalid_certs(options.insecure);
+
+    if let Some(proxy) = &options.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| anyhow::anyhow!("Invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(path) = &options.ca_file {
+        let pem = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read CA file {}: {}", path, e))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| anyhow::anyhow!("Invalid CA file {}: {}", path, e))?;
+        if certificates.is_empty() {
+            anyhow::bail!("No certificates in CA file {}", path);
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some(path) = &options.client_cert {
+        let mut pem = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read client certificate {}: {}", path, e))?;
+        if let Some(key_path) = &options.client_key {
+            let key = std::fs::read(key_path)
+                .map_err(|e| anyhow::anyhow!("Cannot read client key {}: {}", key_path, e))?;
+            pem.push(b'\n');
+            pem.extend_from_slice(&key);
+        }
+        let identity = reqwest::Identity::from_pem(&pem)
+            .map_err(|e| anyhow::anyhow!("Invalid client certificate {}: {}", path, e))?;
+        builder = builder.identity(identity);
+    } else if options.client_key.is_some() {
+        anyhow::bail!("client_key requires client_cert");
+    }
+
+    // Binding to the unspecified address of a family restricts connections to it
+    match options.ip_family {
+        Some(IpFamily::Ipv4) => builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        Some(IpFamily::Ipv6) => builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        Some(IpFamily::Both) | None => {}
+    }
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clients_are_shared() {
+        let clients = HttpClients::default();
+        let short = HttpOptions {
+            timeout: Some(5),
+            ..Default::default()
+        };
+        clients.get(&HttpOptions::default()).unwrap();
+        clients.get(&short).unwrap();
+        assert_eq!(clients.clients.lock().unwrap().len(), 1);
+
+        let socks = HttpOptions {
+            proxy: Some("socks5h://127.0.0.1:1080".to_string()),
+            ip_family: Some(IpFamily::Ipv4),
+            ..Default::default()
+        };
+        clients.get(&socks).unwrap();
+        assert_eq!(clients.clients.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let missing_ca = HttpOptions {
+            ca_file: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(build_client(&missing_ca).is_err());
+        let key_only = HttpOptions {
+            client_key: Some("/nonexistent/key.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(build_client(&key_only).is_err());
+    }
+}
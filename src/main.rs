@@ -1,16 +1,34 @@
 mod config;
+mod debounce;
+mod dhcp;
+mod filter;
+mod http;
 mod logging;
+mod neighbor;
 mod netlink;
+mod provider;
+mod retry;
+mod source;
+mod state;
+mod template;
+mod tracker;
 mod web;
 
 use chrono::Local;
 use colored::Colorize;
-use config::ConfigManager;
+use config::{ConfigManager, IpFamily, SuffixMode, WithdrawAction};
+use debounce::Stabilizer;
+use dhcp::DhcpPdSource;
 use logging::log_to_web;
-use netlink::NetlinkMonitor;
+use neighbor::{NeighborEvent, NeighborMonitor};
+use netlink::{AddressEvent, InterfaceFilter, NetlinkMonitor, IFA_F_TENTATIVE};
+use source::{SourceGate, SourceManager};
+use state::StateManager;
 use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
+use tracker::{address_prefix, PrefixTracker};
 use web::AppState;
 
 use clap::Parser;
@@ -31,9 +49,10 @@ struct Args {
     #[arg(short = 'p', long, default_value_t = 3000)]
     port: u16,
 
-    /// Network interface to monitor (e.g., eth0). If not specified, monitors all interfaces.
-    #[arg(short = 'i', long)]
-    interface: Option<String>,
+    /// Network interfaces to monitor (e.g., eth0 or "ppp*"). Repeat or separate with commas.
+    /// If not specified, monitors all interfaces.
+    #[arg(short = 'i', long, value_delimiter = ',')]
+    interface: Vec<String>,
 }
 
 #[tokio::main]
@@ -53,9 +72,11 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize config
     let config_manager = ConfigManager::new(&args.config).await;
+    let state_manager = StateManager::new(&args.config).await;
 
     // Create channels
     let (netlink_tx, mut netlink_rx) = broadcast::channel(16);
+    let (neighbor_tx, mut neighbor_rx) = broadcast::channel(16);
     let (log_tx, _) = broadcast::channel(100);
 
     // Shared state
@@ -63,37 +84,53 @@ async fn main() -> anyhow::Result<()> {
         config_manager: config_manager.clone(),
         log_tx: log_tx.clone(),
         recent_logs: Arc::new(RwLock::new(VecDeque::new())),
+        neighbors: Default::default(),
+        dhcp_pd: Arc::new(DhcpPdSource::new(netlink_tx.clone())),
+        state_manager,
+        retries: Default::default(),
+        http: Default::default(),
     };
 
-    // Resolve interface index if interface name is provided
-    let interface_index = if let Some(iface_name) = args.interface {
-        match get_interface_index(&iface_name) {
-            Ok(idx) => Some(idx),
-            Err(e) => {
+    // Interfaces are matched by name, so they may come and go at runtime
+    let interface_filter = InterfaceFilter::new(args.interface);
+
+    // Start Netlink Monitor
+    let run_on_startup = config_manager.get_run_on_startup().await;
+    let source_gate = Arc::new(SourceGate::default());
+    let monitor = NetlinkMonitor::new(
+        netlink_tx.clone(),
+        run_on_startup,
+        interface_filter.clone(),
+        source_gate.clone(),
+    );
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = monitor.run().await {
                 let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
                 eprintln!(
-                    "{} {} Error resolving interface '{}': {}",
+                    "{} {} Netlink monitor error: {}. Retrying in 5s...",
                     timestamp,
                     "[Error]".red(),
-                    iface_name,
                     e
                 );
-                return Err(e);
             }
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
         }
-    } else {
-        None
-    };
+    });
 
-    // Start Netlink Monitor
-    let run_on_startup = config_manager.get_run_on_startup().await;
-    let monitor = NetlinkMonitor::new(netlink_tx, run_on_startup, interface_index);
+    // Start DHCPv6-PD lease polling, idle unless lease files are configured
+    let dhcp_pd = state.dhcp_pd.clone();
+    let dhcp_config = config_manager.clone();
+    tokio::spawn(async move { dhcp_pd.run(dhcp_config).await });
+
+    // Start Neighbor Monitor, used by tasks that follow a LAN host
+    let neighbor_monitor = NeighborMonitor::new(neighbor_tx, state.neighbors.clone());
     tokio::spawn(async move {
         loop {
-            if let Err(e) = monitor.run().await {
+            if let Err(e) = neighbor_monitor.run().await {
                 let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
                 eprintln!(
-                    "{} {} Netlink monitor error: {}. Retrying in 5s...",
+                    "{} {} Neighbor monitor error: {}. Retrying in 5s...",
                     timestamp,
                     "[Error]".red(),
                     e
@@ -116,102 +153,616 @@ async fn main() -> anyhow::Result<()> {
         "[Init]".green()
     );
 
-    // Initialize last_prefix based on current state and config
-    let mut last_prefix: Option<u128> = None;
-    let initial_ip = match NetlinkMonitor::get_current_ipv6(interface_index).await {
-        Ok(Some(ip)) => {
-            last_prefix = Some(get_prefix_64(ip));
-            let msg = format!("Initial IP {} detected.", ip);
-            // println!("{}", msg);
-            let log_limit = config_manager.get_log_limit().await;
+    // Initialize per-interface prefixes based on current state and config
+    let mut tracker = PrefixTracker::default();
+    let mut startup_events = Vec::new();
+    let config = config_manager.config.read().await.clone();
+    let (ipv6, ipv4, errors) =
+        source::select_addresses(&config, &interface_filter, &state.http).await;
+    for error in errors {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        eprintln!(
+            "{} {} Failed to fetch initial IP: {}",
+            timestamp,
+            "[Error]".red(),
+            error
+        );
+    }
+
+    // Poll the configured sources, deciding which one each address family comes from.
+    // The first poll waits an interval, the startup selection is handled below.
+    let source_manager = SourceManager::new(netlink_tx.clone(), source_gate.clone());
+    for msg in source_manager.prime(&config, &ipv6, &ipv4) {
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Source",
+            "info",
+            &msg,
+            config.log_limit,
+        )
+        .await;
+    }
+    let source_state = state.clone();
+    let source_filter = interface_filter.clone();
+    tokio::spawn(async move { source_manager.run(source_state, source_filter).await });
+    // Already filtered by the address filter while selecting
+    for event in ipv6.addresses.into_iter().chain(ipv4.addresses) {
+        let prefix = address_prefix(event.address, config.prefix_len);
+        // Only the first address of each interface/prefix is interesting
+        if !tracker.observe(&event.interface(), prefix, event.address, event.if_index) {
+            continue;
+        }
+        let msg = format!("Initial IP {} detected.", event);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "System",
+            "info",
+            &msg,
+            config.log_limit,
+        )
+        .await;
+
+        // A prefix that changed while we were down is a change like any other
+        let interface = event.interface();
+        let last_known = state
+            .state_manager
+            .get_prefix(&interface, event.is_ipv4())
+            .await;
+        let changed_while_down = last_known != Some(prefix);
+        persist_prefix(&state, &interface, event.is_ipv4(), Some(prefix)).await;
+        startup_events.push((event, changed_while_down));
+    }
+
+    for (event, changed_while_down) in startup_events {
+        if !run_on_startup && !changed_while_down {
+            continue;
+        }
+        let tasks = config_manager.get_tasks().await;
+        let log_limit = config_manager.get_log_limit().await;
+        let prefix_len = config_manager.get_prefix_len().await;
+
+        let msg = if changed_while_down {
+            format!(
+                "Startup execution: prefix changed since last run: {}",
+                event
+            )
+        } else {
+            format!("Startup execution: prefix detected: {}", event)
+        };
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Startup",
+            "info",
+            &msg,
+            log_limit,
+        )
+        .await;
+
+        let counterpart = tracker.current_address(&event.interface(), !event.is_ipv4());
+        process_tasks(&state, &tasks, &event, counterpart, prefix_len, "Startup").await;
+    }
+
+    // Shutdown signal
+    let mut shutdown = Box::pin(shutdown_signal());
+
+    // Prefix changes waiting out the stabilization window
+    let mut stabilizer = Stabilizer::default();
+
+    // The neighbor monitor keeps its sender for good, but a closed channel must not spin the loop
+    let mut neighbors_open = true;
+
+    // Catches changes whose netlink events were lost
+    let mut reconcile_timer = Box::pin(tokio::time::sleep(reconcile_delay(&config_manager).await));
+
+    // Main loop: Process IPv6 changes
+    loop {
+        tokio::select! {
+            result = netlink_rx.recv() => {
+                match result {
+                    Ok(event) => handle_address_event(&state, &mut tracker, &mut stabilizer, event).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        let msg = format!("Event queue overflowed, {} events dropped. Reconciling.", skipped);
+                        let log_limit = config_manager.get_log_limit().await;
+                        log_to_web(&state.log_tx, &state.recent_logs, "System", "error", &msg, log_limit).await;
+                        reconcile(&state, &mut tracker, &mut stabilizer, &interface_filter, &source_gate).await;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            result = neighbor_rx.recv(), if neighbors_open => {
+                match result {
+                    Ok(event) => handle_neighbor_event(&state, &tracker, event).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        let msg = format!("Neighbor event queue overflowed, {} events dropped. Reloading the neighbor table.", skipped);
+                        let log_limit = config_manager.get_log_limit().await;
+                        log_to_web(&state.log_tx, &state.recent_logs, "System", "error", &msg, log_limit).await;
+                        resync_neighbors(&state, &tracker).await;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => neighbors_open = false,
+                }
+            }
+            _ = debounce::wait_until(stabilizer.next_deadline()) => {
+                settle(&state, &mut tracker, &mut stabilizer).await;
+            }
+            _ = &mut reconcile_timer => {
+                if config_manager.config.read().await.reconcile_interval > 0 {
+                    reconcile(&state, &mut tracker, &mut stabilizer, &interface_filter, &source_gate).await;
+                }
+                reconcile_timer.set(tokio::time::sleep(reconcile_delay(&config_manager).await));
+            }
+            _ = &mut shutdown => {
+                println!("{} {} Received termination signal, shutting down...", Local::now().format("%Y-%m-%d %H:%M:%S"), "[System]".yellow());
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Time until the next reconciliation. While disabled, the setting is checked again every minute.
+async fn reconcile_delay(config_manager: &ConfigManager) -> std::time::Duration {
+    match config_manager.config.read().await.reconcile_interval {
+        0 => std::time::Duration::from_secs(60),
+        secs => std::time::Duration::from_secs(secs),
+    }
+}
+
+/// Re-read the kernel addresses and process any change whose event never arrived
+async fn reconcile(
+    state: &AppState,
+    tracker: &mut PrefixTracker,
+    stabilizer: &mut Stabilizer,
+    interface_filter: &InterfaceFilter,
+    source_gate: &SourceGate,
+) {
+    let config = state.config_manager.config.read().await.clone();
+    let kernel = match NetlinkMonitor::get_current_addresses(interface_filter).await {
+        Ok(events) => events,
+        Err(e) => {
+            let msg = format!("Reconciliation failed: {}", e);
             log_to_web(
                 &state.log_tx,
                 &state.recent_logs,
                 "System",
+                "error",
+                &msg,
+                config.log_limit,
+            )
+            .await;
+            return;
+        }
+    };
+    let kernel: Vec<AddressEvent> = kernel
+        .into_iter()
+        .filter(|event| config.address_filter.reject_reason(event).is_none())
+        .collect();
+
+    // Families taken from another source are not netlink's to correct
+    let missed = tracker.reconcile(&kernel, config.prefix_len, |ipv4| source_gate.allows(ipv4));
+    for event in missed {
+        let msg = format!("Reconciliation found a missed change: {}", event);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Reconcile",
+            "info",
+            &msg,
+            config.log_limit,
+        )
+        .await;
+        handle_address_event(state, tracker, stabilizer, event).await;
+    }
+}
+
+async fn handle_address_event(
+    state: &AppState,
+    tracker: &mut PrefixTracker,
+    stabilizer: &mut Stabilizer,
+    event: AddressEvent,
+) {
+    let config_manager = &state.config_manager;
+    let prefix_len = config_manager.get_prefix_len().await;
+    let log_limit = config_manager.get_log_limit().await;
+
+    // Not usable while Duplicate Address Detection runs. The event without the flag follows once it completes.
+    if event.has_flag(IFA_F_TENTATIVE) {
+        stabilizer.track_tentative(&event);
+        return;
+    }
+    let dad_completed = stabilizer.dad_completed(event.address);
+
+    if !event.is_active() {
+        let Some(prefix) = tracker.withdraw(&event.interface(), event.address) else {
+            // Possibly the last address of a prefix still waiting to settle
+            let Some((pending, withdrawn)) = stabilizer.withdraw(&event) else {
+                return;
+            };
+            let msg = format!("Superseded before settling: {}", pending);
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                "Netlink",
                 "info",
                 &msg,
                 log_limit,
             )
             .await;
-            Some(ip)
+            // The settled prefix it was going to replace is gone as well, and the record still holds it
+            if let Some(withdrawn) = withdrawn {
+                let tasks = config_manager.get_tasks().await;
+                let counterpart =
+                    tracker.current_address(&withdrawn.interface(), !withdrawn.is_ipv4());
+                process_withdrawal(
+                    state,
+                    &tasks,
+                    &withdrawn,
+                    counterpart,
+                    prefix_len,
+                    log_limit,
+                    "Netlink",
+                )
+                .await;
+            }
+            return;
+        };
+        persist_prefix(state, &event.interface(), event.is_ipv4(), None).await;
+
+        // A new prefix waiting to settle replaces it, unless it disappears before settling
+        let replaced = stabilizer.settled_withdrawn(&event);
+        let msg = if replaced {
+            format!(
+                "Prefix {} withdrawn: {}. A new prefix is waiting to settle.",
+                prefix, event
+            )
+        } else {
+            format!("Prefix {} withdrawn: {}", prefix, event)
+        };
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Netlink",
+            "info",
+            &msg,
+            log_limit,
+        )
+        .await;
+        if replaced {
+            return;
         }
-        Err(e) => {
-            let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-            eprintln!(
-                "{} {} Failed to fetch initial IP: {}",
-                timestamp,
-                "[Error]".red(),
-                e
-            );
-            None
+
+        let tasks = config_manager.get_tasks().await;
+        let counterpart = tracker.current_address(&event.interface(), !event.is_ipv4());
+        process_withdrawal(
+            state,
+            &tasks,
+            &event,
+            counterpart,
+            prefix_len,
+            log_limit,
+            "Netlink",
+        )
+        .await;
+        return;
+    }
+
+    if let Some(reason) = config_manager
+        .get_address_filter()
+        .await
+        .reject_reason(&event)
+    {
+        if std::env::var("DEBUG_DUPLICATE").is_ok() {
+            let msg = format!("Ignored IP: {} ({})", event, reason);
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                "Netlink",
+                "debug",
+                &msg,
+                log_limit,
+            )
+            .await;
         }
-        _ => None,
-    };
+        return;
+    }
 
-    if run_on_startup {
-        if let Some(ip) = initial_ip {
-            let tasks = config_manager.get_tasks().await;
-            let log_limit = config_manager.get_log_limit().await;
+    let current_prefix = address_prefix(event.address, prefix_len);
+    let window = config_manager.config.read().await.stabilization_window;
 
-            let msg = format!("Startup execution: IPv6 prefix detected: {}", ip);
+    // With a stabilization window the tracker keeps the settled prefix until the new one settles
+    let is_new = if window == 0 {
+        tracker.observe(
+            &event.interface(),
+            current_prefix,
+            event.address,
+            event.if_index,
+        )
+    } else if tracker.current_prefix(&event.interface(), event.is_ipv4()) == Some(current_prefix) {
+        tracker.observe(
+            &event.interface(),
+            current_prefix,
+            event.address,
+            event.if_index,
+        );
+        // The settled prefix came back before the new one settled
+        if let Some(pending) = stabilizer.cancel(&event.interface(), event.is_ipv4()) {
+            let msg = format!(
+                "Superseded before settling: {}. Prefix {} is back.",
+                pending, current_prefix
+            );
             log_to_web(
                 &state.log_tx,
                 &state.recent_logs,
-                "Startup",
+                "Netlink",
                 "info",
                 &msg,
                 log_limit,
             )
             .await;
+            return;
+        }
+        false
+    } else {
+        !stabilizer.join(&event, current_prefix)
+    };
 
-            process_tasks(&state, &tasks, ip, log_limit, "Startup").await;
+    if !is_new {
+        if std::env::var("DEBUG_DUPLICATE").is_ok() {
+            let msg = format!("Duplicate IP: {}, Prefix: {}", event, current_prefix);
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                "Netlink",
+                "debug",
+                &msg,
+                log_limit,
+            )
+            .await;
         }
+        return;
     }
 
-    // Shutdown signal
-    let mut shutdown = Box::pin(shutdown_signal());
+    let msg = if event.is_ipv4() {
+        format!("New IPv4 address: {}", event)
+    } else {
+        format!("New IPv6 prefix from: {}", event)
+    };
 
-    // Main loop: Process IPv6 changes
-    loop {
-        tokio::select! {
-            result = netlink_rx.recv() => {
-                match result {
-                    Ok(ip) => {
-                        let current_prefix = get_prefix_64(ip);
-
-                        if Some(current_prefix) == last_prefix {
-                            if std::env::var("DEBUG_DUPLICATE").is_ok() {
-                                // 截断后缀零，仅保留前64位前缀
-                                let prefix_trunc = current_prefix >> 64;
-                                let msg = format!("Duplicate IP: {}, Prefix: {:x}", ip, prefix_trunc);
-                                let log_limit = config_manager.get_log_limit().await;
-                                log_to_web(&state.log_tx, &state.recent_logs, "Netlink", "debug", &msg, log_limit).await;
-                            }
-                            continue;
-                        }
-                        last_prefix = Some(current_prefix);
-
-                        let tasks = config_manager.get_tasks().await;
-                        let log_limit = config_manager.get_log_limit().await;
+    // Log detection
+    log_to_web(
+        &state.log_tx,
+        &state.recent_logs,
+        "Netlink",
+        "info",
+        &msg,
+        log_limit,
+    )
+    .await;
 
-                        let msg = format!("New IPv6 prefix from: {}", ip);
+    if window > 0 {
+        // An address that just passed DAD is ready, no need to wait out the window
+        let wait = if dad_completed {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::from_secs(window)
+        };
+        let superseded = stabilizer.defer(event, current_prefix, wait, tokio::time::Instant::now());
+        let waiting = if dad_completed {
+            "Duplicate address detection completed, releasing the new prefix.".to_string()
+        } else {
+            format!("Waiting {}s for the prefix to settle.", window)
+        };
+        let msg = match superseded {
+            Some(previous) => format!("Superseded before settling: {}. {}", previous, waiting),
+            None => waiting,
+        };
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Netlink",
+            "info",
+            &msg,
+            log_limit,
+        )
+        .await;
+        return;
+    }
 
-                        // Log detection
-                        log_to_web(&state.log_tx, &state.recent_logs, "Netlink", "info", &msg, log_limit).await;
+    persist_prefix(
+        state,
+        &event.interface(),
+        event.is_ipv4(),
+        Some(current_prefix),
+    )
+    .await;
 
-                        process_tasks(&state, &tasks, ip, log_limit, "Netlink").await;
-                    }
-                    Err(_) => break,
-                }
-            }
-            _ = &mut shutdown => {
-                println!("{} {} Received termination signal, shutting down...", Local::now().format("%Y-%m-%d %H:%M:%S"), "[System]".yellow());
-                break;
+    let tasks = config_manager.get_tasks().await;
+    let counterpart = tracker.current_address(&event.interface(), !event.is_ipv4());
+    process_tasks(state, &tasks, &event, counterpart, prefix_len, "Netlink").await;
+}
+
+/// Run the tasks for prefix changes that have been stable for the whole window
+async fn settle(state: &AppState, tracker: &mut PrefixTracker, stabilizer: &mut Stabilizer) {
+    let config_manager = &state.config_manager;
+    let prefix_len = config_manager.get_prefix_len().await;
+    let log_limit = config_manager.get_log_limit().await;
+
+    for settled in stabilizer.take_due(tokio::time::Instant::now()) {
+        let event = settled.event;
+        let interface = event.interface();
+        // Only now does the new prefix replace the settled one
+        for address in settled.addresses {
+            tracker.observe(&interface, settled.prefix, address, event.if_index);
+        }
+        persist_prefix(state, &interface, event.is_ipv4(), Some(settled.prefix)).await;
+
+        let msg = format!("Prefix settled: {}", event);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Netlink",
+            "info",
+            &msg,
+            log_limit,
+        )
+        .await;
+
+        let tasks = config_manager.get_tasks().await;
+        let counterpart = tracker.current_address(&interface, !event.is_ipv4());
+        process_tasks(state, &tasks, &event, counterpart, prefix_len, "Netlink").await;
+    }
+}
+
+/// A LAN host showed up with a new address: run the tasks following it if the address is under a current prefix
+async fn handle_neighbor_event(state: &AppState, tracker: &PrefixTracker, event: NeighborEvent) {
+    let config_manager = &state.config_manager;
+    let prefix_len = config_manager.get_prefix_len().await;
+    let log_limit = config_manager.get_log_limit().await;
+    let tasks = config_manager.get_tasks().await;
+
+    for task in &tasks {
+        if !task.enabled || task.suffix_mode != SuffixMode::Neighbor {
+            continue;
+        }
+        let Some(mac) = task.mac.as_deref().and_then(|m| web::parse_mac(m).ok()) else {
+            continue;
+        };
+        if mac != event.mac {
+            continue;
+        }
+
+        let task_prefix_len = task.effective_prefix_len(prefix_len);
+        let current = tracker
+            .current_addresses(false)
+            .find_map(|(interface, address)| {
+                let IpAddr::V6(v6) = address else {
+                    return None;
+                };
+                // The host may sit on a downstream subnet carved out of this prefix
+                let network = web::apply_subnet_id(v6, task, task_prefix_len).ok()?;
+                let same_network = web::network_prefix(network, task_prefix_len)
+                    == web::network_prefix(event.address, task_prefix_len);
+                (same_network && task.watches_interface(interface))
+                    .then_some((interface, v6, network))
+            });
+        let Some((interface, ipv6, network)) = current else {
+            continue;
+        };
+
+        // Only publish the address the task resolves to, not every temporary address of the host
+        let resolved = state
+            .neighbors
+            .read()
+            .unwrap()
+            .find(&mac, network, task_prefix_len);
+        if resolved != Some(event.address) {
+            continue;
+        }
+
+        let msg = format!("Task [{}]: Host discovered: {}", task.name, event);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "Neighbor",
+            "info",
+            &msg,
+            log_limit,
+        )
+        .await;
+
+        let ipv4 = match tracker.current_address(interface, true) {
+            Some(IpAddr::V4(v4)) => Some(v4),
+            _ => None,
+        };
+        spawn_task_run(
+            state,
+            task,
+            Some(ipv6),
+            ipv4,
+            prefix_len,
+            Some(interface),
+            "Neighbor",
+        );
+    }
+}
+
+/// Reload the neighbor table after lost events and run the tasks whose host may have moved meanwhile
+async fn resync_neighbors(state: &AppState, tracker: &PrefixTracker) {
+    let config_manager = &state.config_manager;
+    match NeighborMonitor::dump_table().await {
+        Ok(table) => *state.neighbors.write().unwrap() = table,
+        Err(e) => {
+            let log_limit = config_manager.get_log_limit().await;
+            let msg = format!("Failed to reload the neighbor table: {}", e);
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                "System",
+                "error",
+                &msg,
+                log_limit,
+            )
+            .await;
+            return;
+        }
+    }
+
+    let prefix_len = config_manager.get_prefix_len().await;
+    let tasks = config_manager.get_tasks().await;
+    for task in &tasks {
+        if !task.enabled || task.suffix_mode != SuffixMode::Neighbor {
+            continue;
+        }
+        for (interface, address) in tracker.current_addresses(false) {
+            let IpAddr::V6(ipv6) = address else {
+                continue;
+            };
+            if !task.watches_interface(interface) || !neighbor_known(state, task, ipv6, prefix_len)
+            {
+                continue;
             }
+            // Runs whose address is already published are skipped by spawn_task_run
+            let ipv4 = match tracker.current_address(interface, true) {
+                Some(IpAddr::V4(v4)) => Some(v4),
+                _ => None,
+            };
+            spawn_task_run(
+                state,
+                task,
+                Some(ipv6),
+                ipv4,
+                prefix_len,
+                Some(interface),
+                "Neighbor",
+            );
         }
     }
+}
 
-    Ok(())
+/// Whether a Neighbor-mode task's host has been seen under the network derived from `ipv6`.
+/// A bad MAC or subnet ID counts as known, so the run reports it.
+fn neighbor_known(
+    state: &AppState,
+    task: &config::Task,
+    ipv6: Ipv6Addr,
+    default_prefix_len: u8,
+) -> bool {
+    let Some(mac) = task.mac.as_deref().and_then(|m| web::parse_mac(m).ok()) else {
+        return true;
+    };
+    let prefix_len = task.effective_prefix_len(default_prefix_len);
+    let Ok(network) = web::apply_subnet_id(ipv6, task, prefix_len) else {
+        return true;
+    };
+    state
+        .neighbors
+        .read()
+        .unwrap()
+        .find(&mac, network, prefix_len)
+        .is_some()
 }
 
 async fn shutdown_signal() {
@@ -238,37 +789,164 @@ async fn shutdown_signal() {
     }
 }
 
-fn get_interface_index(name: &str) -> anyhow::Result<u32> {
-    let path = format!("/sys/class/net/{}/ifindex", name);
-    let content = std::fs::read_to_string(&path)?;
-    let index = content.trim().parse()?;
-    Ok(index)
-}
-
-fn get_prefix_64(ip: std::net::Ipv6Addr) -> u128 {
-    u128::from(ip) & 0xffff_ffff_ffff_ffff_0000_0000_0000_0000
+/// Sort an event address and the current address of the other family into (IPv6, IPv4)
+fn split_families(
+    address: IpAddr,
+    counterpart: Option<IpAddr>,
+) -> (Option<Ipv6Addr>, Option<Ipv4Addr>) {
+    let mut ipv6 = None;
+    let mut ipv4 = None;
+    for ip in std::iter::once(address).chain(counterpart) {
+        match ip {
+            IpAddr::V6(v6) => ipv6 = Some(v6),
+            IpAddr::V4(v4) => ipv4 = Some(v4),
+        }
+    }
+    (ipv6, ipv4)
 }
 
 async fn process_tasks(
     state: &AppState,
     tasks: &[config::Task],
-    ip: std::net::Ipv6Addr,
-    log_limit: usize,
+    event: &AddressEvent,
+    counterpart: Option<IpAddr>,
+    default_prefix_len: u8,
     source: &str,
 ) {
+    let (ipv6, ipv4) = split_families(event.address, counterpart);
+    let interface = event.interface();
     for task in tasks {
-        if !task.enabled {
+        if !task.enabled
+            || !task.watches_interface(&interface)
+            || !task.address_family.includes(event.is_ipv4())
+        {
             continue;
         }
 
-        let state = state.clone();
-        let task = task.clone();
-        let source = source.to_string();
+        // The host's address under a new prefix arrives as a neighbor event, which runs the task then
+        if task.suffix_mode == SuffixMode::Neighbor
+            && task.address_family != IpFamily::Ipv4
+            && ipv6.is_some_and(|v6| !neighbor_known(state, task, v6, default_prefix_len))
+        {
+            let log_limit = state.config_manager.get_log_limit().await;
+            let msg = format!(
+                "Task [{}]: Host not seen under the new prefix yet, waiting for it",
+                task.name
+            );
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                source,
+                "info",
+                &msg,
+                log_limit,
+            )
+            .await;
+            continue;
+        }
 
-        tokio::spawn(async move {
-            match web::combine_ip(ip, &task.suffix) {
-                Ok(combined) => {
-                    let log_msg = format!("Task [{}]: Running for {}", task.name, combined);
+        spawn_task_run(
+            state,
+            task,
+            ipv6,
+            ipv4,
+            default_prefix_len,
+            Some(&interface),
+            source,
+        );
+    }
+}
+
+/// Remember what a task published, so unchanged addresses are not pushed again after a restart
+async fn record_push(
+    state: &AppState,
+    task: &config::Task,
+    published: Option<&web::TemplateVars>,
+    log_limit: usize,
+) {
+    let address = published.map(|vars| vars.combined_ip);
+    let ipv4 = published.and_then(|vars| vars.published_ipv4(task));
+    if let Err(e) = state
+        .state_manager
+        .set_pushed(&task.id, address, ipv4)
+        .await
+    {
+        let msg = format!("Task [{}]: Failed to save state: {}", task.name, e);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "System",
+            "error",
+            &msg,
+            log_limit,
+        )
+        .await;
+    }
+}
+
+/// Remember the current prefix of an interface, `None` once withdrawn
+async fn persist_prefix(state: &AppState, interface: &str, ipv4: bool, prefix: Option<IpAddr>) {
+    if let Err(e) = state
+        .state_manager
+        .set_prefix(interface, ipv4, prefix)
+        .await
+    {
+        let log_limit = state.config_manager.get_log_limit().await;
+        let msg = format!("Failed to save state: {}", e);
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            "System",
+            "error",
+            &msg,
+            log_limit,
+        )
+        .await;
+    }
+}
+
+/// Run a task's webhook in the background and log the outcome
+fn spawn_task_run(
+    state: &AppState,
+    task: &config::Task,
+    ipv6: Option<Ipv6Addr>,
+    ipv4: Option<Ipv4Addr>,
+    default_prefix_len: u8,
+    interface: Option<&str>,
+    source: &str,
+) {
+    let state = state.clone();
+    let task = task.clone();
+    let interface = interface.map(str::to_string);
+    let source = source.to_string();
+    // Taken before spawning so runs are ordered as their prefix changes arrived
+    let generation = state.retries.begin(&task.id);
+
+    tokio::spawn(async move {
+        let log_limit = state.config_manager.get_log_limit().await;
+        let last = state.state_manager.last_pushed(&task.id).await;
+        let previous = last.as_ref().and_then(|last| last.address);
+        let vars = web::TemplateVars::for_task(
+            &task,
+            ipv6,
+            ipv4,
+            default_prefix_len,
+            &state.neighbors.read().unwrap(),
+        );
+        match vars {
+            Ok(vars) => {
+                let vars = vars.with_run(
+                    &task,
+                    &source,
+                    interface.as_deref(),
+                    previous,
+                    default_prefix_len,
+                );
+                if vars.is_published(&task, last.as_ref()) {
+                    let log_msg = format!(
+                        "Task [{}]: {} already published, skipping",
+                        task.name, vars.combined_ip
+                    );
                     log_to_web(
                         &state.log_tx,
                         &state.recent_logs,
@@ -278,48 +956,198 @@ async fn process_tasks(
                         log_limit,
                     )
                     .await;
+                    return;
+                }
 
-                    match web::send_webhook(&task, ip, combined, None).await {
-                        Ok(status) => {
-                            let success_msg =
-                                format!("Task [{}]: Success (HTTP {})", task.name, status);
-                            log_to_web(
-                                &state.log_tx,
-                                &state.recent_logs,
-                                &source,
-                                "success",
-                                &success_msg,
-                                log_limit,
-                            )
-                            .await;
-                        }
-                        Err(e) => {
-                            let err_msg = format!("Task [{}]: Failed: {}", task.name, e);
-                            log_to_web(
-                                &state.log_tx,
-                                &state.recent_logs,
-                                &source,
-                                "error",
-                                &err_msg,
-                                log_limit,
+                let log_msg = format!("Task [{}]: Running for {}", task.name, vars.combined_ip);
+                log_to_web(
+                    &state.log_tx,
+                    &state.recent_logs,
+                    &source,
+                    "info",
+                    &log_msg,
+                    log_limit,
+                )
+                .await;
+
+                match retry::send_with_retry(&state, &task, &vars, generation, &source, log_limit)
+                    .await
+                {
+                    Ok(outcome) => {
+                        record_push(&state, &task, Some(&vars), log_limit).await;
+                        let success_msg = format!("Task [{}]: Success ({})", task.name, outcome);
+                        log_to_web(
+                            &state.log_tx,
+                            &state.recent_logs,
+                            &source,
+                            "success",
+                            &success_msg,
+                            log_limit,
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let (level, err_msg) = if e.is::<retry::Superseded>() {
+                            (
+                                "info",
+                                format!(
+                                    "Task [{}]: Retry for {} cancelled, a newer run took over",
+                                    task.name, vars.combined_ip
+                                ),
                             )
-                            .await;
-                        }
+                        } else {
+                            ("error", format!("Task [{}]: Failed: {}", task.name, e))
+                        };
+                        log_to_web(
+                            &state.log_tx,
+                            &state.recent_logs,
+                            &source,
+                            level,
+                            &err_msg,
+                            log_limit,
+                        )
+                        .await;
                     }
                 }
-                Err(e) => {
-                    let err_msg = format!("Task [{}]: IP combination failed: {}", task.name, e);
-                    log_to_web(
-                        &state.log_tx,
-                        &state.recent_logs,
-                        &source,
-                        "error",
-                        &err_msg,
-                        log_limit,
-                    )
-                    .await;
+            }
+            Err(e) => {
+                let err_msg = format!("Task [{}]: IP combination failed: {}", task.name, e);
+                log_to_web(
+                    &state.log_tx,
+                    &state.recent_logs,
+                    &source,
+                    "error",
+                    &err_msg,
+                    log_limit,
+                )
+                .await;
+            }
+        }
+    });
+}
+
+/// Run the `on_withdraw` action of every task bound to the interface that lost its prefix
+async fn process_withdrawal(
+    state: &AppState,
+    tasks: &[config::Task],
+    event: &AddressEvent,
+    counterpart: Option<IpAddr>,
+    default_prefix_len: u8,
+    log_limit: usize,
+    source: &str,
+) {
+    let (ipv6, ipv4) = split_families(event.address, counterpart);
+    let interface = event.interface();
+    for task in tasks {
+        if !task.enabled
+            || !task.watches_interface(&interface)
+            || !task.address_family.includes(event.is_ipv4())
+        {
+            continue;
+        }
+        let Some(action) = task.on_withdraw.clone() else {
+            continue;
+        };
+
+        let state = state.clone();
+        let task = task.clone();
+        let source = source.to_string();
+        let interface = interface.clone();
+        let generation = state.retries.begin(&task.id);
+
+        tokio::spawn(async move {
+            let previous = state
+                .state_manager
+                .last_pushed(&task.id)
+                .await
+                .and_then(|last| last.address);
+            let describe_run = |vars: web::TemplateVars| {
+                vars.with_run(
+                    &task,
+                    &source,
+                    Some(&interface),
+                    previous,
+                    default_prefix_len,
+                )
+            };
+            // What the record holds afterwards: the fallback address, or nothing
+            let result = match action {
+                WithdrawAction::Fallback { address } => match address.parse::<IpAddr>() {
+                    Ok(fallback) => {
+                        let vars = describe_run(web::TemplateVars::fixed(fallback));
+                        retry::send_with_retry(&state, &task, &vars, generation, &source, log_limit)
+                            .await
+                            .map(|outcome| (outcome, Some(vars)))
+                    }
+                    Err(_) => Err(anyhow::anyhow!("Invalid fallback address {}", address)),
+                },
+                WithdrawAction::Webhook {
+                    webhook_url,
+                    webhook_method,
+                    webhook_body,
+                    webhook_headers,
+                } => {
+                    // Same template variables as the regular webhook, describing the withdrawn address
+                    let withdraw_task = task.withdraw_webhook(
+                        webhook_url,
+                        webhook_method,
+                        webhook_body,
+                        webhook_headers,
+                    );
+                    let vars = web::TemplateVars::for_task(
+                        &task,
+                        ipv6,
+                        ipv4,
+                        default_prefix_len,
+                        &state.neighbors.read().unwrap(),
+                    );
+                    match vars {
+                        Ok(vars) => retry::send_with_retry(
+                            &state,
+                            &withdraw_task,
+                            &describe_run(vars),
+                            generation,
+                            &source,
+                            log_limit,
+                        )
+                        .await
+                        .map(|outcome| (outcome, None)),
+                        Err(e) => Err(e),
+                    }
                 }
+            };
+            if let Ok((_, published)) = &result {
+                record_push(&state, &task, published.as_ref(), log_limit).await;
             }
+            let (level, msg) = match result {
+                Ok((outcome, _)) => (
+                    "success",
+                    format!(
+                        "Task [{}]: Withdraw action succeeded ({})",
+                        task.name, outcome
+                    ),
+                ),
+                Err(e) if e.is::<retry::Superseded>() => (
+                    "info",
+                    format!(
+                        "Task [{}]: Withdraw retry cancelled, a newer run took over",
+                        task.name
+                    ),
+                ),
+                Err(e) => (
+                    "error",
+                    format!("Task [{}]: Withdraw action failed: {}", task.name, e),
+                ),
+            };
+            log_to_web(
+                &state.log_tx,
+                &state.recent_logs,
+                &source,
+                level,
+                &msg,
+                log_limit,
+            )
+            .await;
         });
     }
 }
@@ -0,0 +1,309 @@
+use crate::netlink::interface_name;
+use anyhow::Result;
+use chrono::Local;
+use colored::Colorize;
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
+use netlink_packet_route::{
+    neighbour::{NeighbourAddress, NeighbourAttribute, NeighbourMessage, NeighbourState},
+    AddressFamily, RouteNetlinkMessage,
+};
+use netlink_sys::{AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+
+pub type MacAddr = [u8; 6];
+
+/// A global IPv6 address seen (or no longer seen) in the kernel neighbor table
+#[derive(Clone, Debug, PartialEq)]
+pub struct NeighborEvent {
+    pub mac: MacAddr,
+    pub address: Ipv6Addr,
+    pub if_index: u32,
+    pub if_name: Option<String>,
+    pub removed: bool,
+}
+
+impl fmt::Display for NeighborEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.removed {
+            write!(f, "removed ")?;
+        }
+        write!(f, "{} at {} on ", self.address, format_mac(&self.mac))?;
+        match &self.if_name {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "if#{}", self.if_index),
+        }
+    }
+}
+
+pub fn format_mac(mac: &MacAddr) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Global IPv6 addresses currently used by each LAN host, keyed by MAC
+#[derive(Default)]
+pub struct NeighborTable {
+    /// Addresses in the order they were first seen
+    hosts: HashMap<MacAddr, Vec<Ipv6Addr>>,
+}
+
+pub type SharedNeighborTable = Arc<RwLock<NeighborTable>>;
+
+impl NeighborTable {
+    /// Apply an event. Returns true if it added an address not known before.
+    pub fn update(&mut self, event: &NeighborEvent) -> bool {
+        if event.removed {
+            if let Some(addresses) = self.hosts.get_mut(&event.mac) {
+                addresses.retain(|a| *a != event.address);
+                if addresses.is_empty() {
+                    self.hosts.remove(&event.mac);
+                }
+            }
+            return false;
+        }
+        let addresses = self.hosts.entry(event.mac).or_default();
+        if addresses.contains(&event.address) {
+            return false;
+        }
+        addresses.push(event.address);
+        true
+    }
+
+    /// The longest-known address of `mac` inside `network`/`prefix_len`.
+    /// Temporary addresses rotate, so the oldest one is usually the stable address.
+    pub fn find(&self, mac: &MacAddr, network: Ipv6Addr, prefix_len: u8) -> Option<Ipv6Addr> {
+        self.hosts.get(mac)?.iter().copied().find(|address| {
+            crate::web::network_prefix(*address, prefix_len)
+                == crate::web::network_prefix(network, prefix_len)
+        })
+    }
+}
+
+pub struct NeighborMonitor {
+    tx: broadcast::Sender<NeighborEvent>,
+    table: SharedNeighborTable,
+}
+
+impl NeighborMonitor {
+    pub fn new(tx: broadcast::Sender<NeighborEvent>, table: SharedNeighborTable) -> Self {
+        Self { tx, table }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        // NETLINK_ROUTE is 0
+        let mut socket = TokioSocket::new(0)?;
+
+        // RTMGRP_NEIGH = 0x4
+        let addr = SocketAddr::new(0, 0x4);
+        socket.socket_mut().bind(&addr)?;
+
+        println!(
+            "{} {} Neighbor monitor started, listening for LAN host addresses...",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            "[Init]".green()
+        );
+
+        // Entries may have changed while the socket was down
+        self.fetch_existing_neighbors().await?;
+
+        let mut buf = vec![0u8; 8192];
+
+        loop {
+            // recv_from appends to buf
+            socket.recv_from(&mut buf).await?;
+            let len = buf.len();
+            let mut offset = 0;
+
+            while offset < len {
+                let bytes = &buf[offset..len];
+                if bytes.len() < 4 {
+                    break;
+                }
+
+                let msg = match <NetlinkMessage<RouteNetlinkMessage>>::deserialize(bytes) {
+                    Ok(m) => m,
+                    Err(_) => break,
+                };
+
+                let msg_len = msg.header.length as usize;
+                if msg_len == 0 || msg_len > bytes.len() {
+                    break;
+                }
+
+                match msg.payload {
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewNeighbour(neigh_msg)) => {
+                        self.process_message(neigh_msg, false);
+                    }
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelNeighbour(neigh_msg)) => {
+                        self.process_message(neigh_msg, true);
+                    }
+                    _ => {}
+                }
+
+                offset += msg_len;
+            }
+            buf.clear();
+        }
+    }
+
+    /// Load the current neighbor table without announcing the entries
+    async fn fetch_existing_neighbors(&self) -> Result<()> {
+        *self.table.write().unwrap() = Self::dump_table().await?;
+        Ok(())
+    }
+
+    /// Read the kernel neighbor table
+    pub async fn dump_table() -> Result<NeighborTable> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        let mut neighbors = handle
+            .neighbours()
+            .get()
+            .set_family(rtnetlink::IpVersion::V6)
+            .execute();
+        use futures::stream::TryStreamExt;
+
+        let mut table = NeighborTable::default();
+        while let Some(msg) = neighbors.try_next().await.unwrap_or(None) {
+            if let Some(event) = Self::extract_neighbor_from_message(msg, false) {
+                table.update(&event);
+            }
+        }
+        Ok(table)
+    }
+
+    fn process_message(&self, msg: NeighbourMessage, removed: bool) {
+        if let Some(mut event) = Self::extract_neighbor_from_message(msg, removed) {
+            event.if_name = interface_name(event.if_index);
+            if self.table.write().unwrap().update(&event) {
+                let _ = self.tx.send(event);
+            }
+        }
+    }
+
+    /// Parse a neighbor message, keeping only global IPv6 entries with a link-layer address
+    fn extract_neighbor_from_message(
+        msg: NeighbourMessage,
+        removed: bool,
+    ) -> Option<NeighborEvent> {
+        if msg.header.family != AddressFamily::Inet6 {
+            return None;
+        }
+
+        let mut address = None;
+        let mut mac = None;
+        for attr in msg.attributes {
+            match attr {
+                NeighbourAttribute::Destination(NeighbourAddress::Inet6(addr)) => {
+                    address = Some(addr)
+                }
+                NeighbourAttribute::LinkLocalAddress(lladdr) => {
+                    mac = MacAddr::try_from(lladdr.as_slice()).ok()
+                }
+                _ => {}
+            }
+        }
+
+        let address = address?;
+        if address.is_loopback()
+            || address.is_multicast()
+            || address.is_unspecified()
+            || (address.segments()[0] & 0xffc0) == 0xfe80
+        {
+            return None;
+        }
+
+        // A failed entry means the host no longer answers on this address
+        let removed = removed
+            || matches!(
+                msg.header.state,
+                NeighbourState::Failed | NeighbourState::Incomplete
+            );
+
+        Some(NeighborEvent {
+            mac: mac?,
+            address,
+            if_index: msg.header.ifindex,
+            if_name: None,
+            removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const MAC: MacAddr = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn neighbor_message(addr: &str, state: NeighbourState) -> NeighbourMessage {
+        let mut msg = NeighbourMessage::default();
+        msg.header.family = AddressFamily::Inet6;
+        msg.header.ifindex = 3;
+        msg.header.state = state;
+        msg.attributes
+            .push(NeighbourAttribute::Destination(NeighbourAddress::Inet6(
+                Ipv6Addr::from_str(addr).unwrap(),
+            )));
+        msg.attributes
+            .push(NeighbourAttribute::LinkLocalAddress(MAC.to_vec()));
+        msg
+    }
+
+    fn event(addr: &str, removed: bool) -> NeighborEvent {
+        NeighborEvent {
+            mac: MAC,
+            address: Ipv6Addr::from_str(addr).unwrap(),
+            if_index: 3,
+            if_name: None,
+            removed,
+        }
+    }
+
+    #[test]
+    fn test_extract_neighbor() {
+        let msg = neighbor_message("2001:db8:1::abcd", NeighbourState::Reachable);
+        let parsed = NeighborMonitor::extract_neighbor_from_message(msg, false).unwrap();
+        assert_eq!(parsed, event("2001:db8:1::abcd", false));
+        assert_eq!(format_mac(&parsed.mac), "52:54:00:12:34:56");
+
+        let msg = neighbor_message("2001:db8:1::abcd", NeighbourState::Failed);
+        let parsed = NeighborMonitor::extract_neighbor_from_message(msg, false).unwrap();
+        assert!(parsed.removed);
+
+        let msg = neighbor_message("fe80::1", NeighbourState::Reachable);
+        assert!(NeighborMonitor::extract_neighbor_from_message(msg, false).is_none());
+    }
+
+    #[test]
+    fn test_table_finds_oldest_address_under_prefix() {
+        let mut table = NeighborTable::default();
+        assert!(table.update(&event("2001:db8:1::aaaa", false)));
+        assert!(table.update(&event("2001:db8:2::bbbb", false)));
+        assert!(table.update(&event("2001:db8:2::cccc", false)));
+        // Refreshes are not news
+        assert!(!table.update(&event("2001:db8:2::bbbb", false)));
+
+        let network = Ipv6Addr::from_str("2001:db8:2::").unwrap();
+        assert_eq!(
+            table.find(&MAC, network, 64),
+            Some(Ipv6Addr::from_str("2001:db8:2::bbbb").unwrap())
+        );
+
+        table.update(&event("2001:db8:2::bbbb", true));
+        assert_eq!(
+            table.find(&MAC, network, 64),
+            Some(Ipv6Addr::from_str("2001:db8:2::cccc").unwrap())
+        );
+        let other = Ipv6Addr::from_str("2001:db8:3::").unwrap();
+        assert_eq!(table.find(&MAC, other, 64), None);
+    }
+}
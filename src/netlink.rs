@@ -1,48 +1,222 @@
+use crate::source::SourceGate;
 use anyhow::Result;
 use chrono::Local;
 use colored::Colorize;
 use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
 use netlink_packet_route::{
-    address::{AddressAttribute, AddressHeaderFlag, AddressMessage},
+    address::{AddressAttribute, AddressMessage},
+    link::{LinkAttribute, LinkMessage},
     AddressFamily, RouteNetlinkMessage,
 };
 use netlink_sys::{AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket};
-use std::net::{IpAddr, Ipv6Addr};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 use tokio::sync::broadcast;
 
+// Address flags (IFA_F_*), combined from the message header and IFA_FLAGS
+/// RFC 4941 temporary address (shares its value with IFA_F_SECONDARY)
+pub const IFA_F_TEMPORARY: u32 = 0x01;
+pub const IFA_F_DEPRECATED: u32 = 0x20;
+pub const IFA_F_TENTATIVE: u32 = 0x40;
+pub const IFA_F_MANAGETEMPADDR: u32 = 0x100;
+pub const IFA_F_STABLE_PRIVACY: u32 = 0x800;
+
+/// Lifetime value the kernel uses for "forever"
+pub const INFINITY_LIFE_TIME: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressEventKind {
+    /// Address added or refreshed (RTM_NEWADDR)
+    Added,
+    /// Address still present but no longer preferred, e.g. the ISP stopped announcing the prefix
+    Deprecated,
+    /// Address removed (RTM_DELADDR)
+    Removed,
+}
+
+/// An address reported by the kernel, with the metadata that came with it
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressEvent {
+    pub kind: AddressEventKind,
+    pub address: IpAddr,
+    /// Prefix length the kernel holds for this address
+    pub prefix_len: u8,
+    pub if_index: u32,
+    /// Interface name, if it could be resolved from the index
+    pub if_name: Option<String>,
+    /// IFA_F_* bitmask
+    pub flags: u32,
+    /// Valid lifetime in seconds from IFA_CACHEINFO
+    pub valid_lft: Option<u32>,
+    /// Preferred lifetime in seconds from IFA_CACHEINFO
+    pub preferred_lft: Option<u32>,
+}
+
+impl AddressEvent {
+    pub fn is_ipv4(&self) -> bool {
+        self.address.is_ipv4()
+    }
+
+    pub fn has_flag(&self, flags: u32) -> bool {
+        self.flags & flags != 0
+    }
+
+    /// Whether the address is usable (neither deprecated nor removed)
+    pub fn is_active(&self) -> bool {
+        self.kind == AddressEventKind::Added
+    }
+
+    /// Whether the kernel reported the address, as opposed to a DHCP-PD lease or an address source
+    pub fn is_kernel(&self) -> bool {
+        self.if_index != 0
+    }
+
+    /// Interface name for display, falling back to the index
+    pub fn interface(&self) -> String {
+        self.if_name
+            .clone()
+            .unwrap_or_else(|| format!("if#{}", self.if_index))
+    }
+}
+
+impl fmt::Display for AddressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            AddressEventKind::Added => {}
+            AddressEventKind::Deprecated => write!(f, "deprecated ")?,
+            AddressEventKind::Removed => write!(f, "removed ")?,
+        }
+        write!(
+            f,
+            "{}/{} on {}",
+            self.address,
+            self.prefix_len,
+            self.interface()
+        )?;
+        if let (Some(valid), Some(preferred)) = (self.valid_lft, self.preferred_lft) {
+            write!(
+                f,
+                " (valid {}, preferred {})",
+                format_lifetime(valid),
+                format_lifetime(preferred)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_lifetime(secs: u32) -> String {
+    if secs == INFINITY_LIFE_TIME {
+        "forever".to_string()
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Interface name patterns to monitor, e.g. `ppp*` or `eth0`. No patterns match every interface.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceFilter {
+    patterns: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self { patterns }
+    }
+
+    pub fn matches(&self, name: Option<&str>) -> bool {
+        if self.patterns.is_empty() {
+            return true;
+        }
+        match name {
+            Some(name) => self.patterns.iter().any(|p| glob_match(p, name)),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for InterfaceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.patterns.is_empty() {
+            write!(f, "all interfaces")
+        } else {
+            write!(f, "{}", self.patterns.join(", "))
+        }
+    }
+}
+
+/// Shell-style matching supporting `*` and `?`
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name index it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 pub struct NetlinkMonitor {
-    tx: broadcast::Sender<Ipv6Addr>,
+    tx: broadcast::Sender<AddressEvent>,
     run_on_startup: bool,
-    interface_index: Option<u32>,
+    filter: InterfaceFilter,
+    /// Interface index -> name, kept current from link messages
+    links: RwLock<HashMap<u32, String>>,
+    /// Whether netlink is the source in use for each family
+    gate: Arc<SourceGate>,
 }
 
 impl NetlinkMonitor {
     pub fn new(
-        tx: broadcast::Sender<Ipv6Addr>,
-        _run_on_startup: bool,
-        interface_index: Option<u32>,
+        tx: broadcast::Sender<AddressEvent>,
+        run_on_startup: bool,
+        filter: InterfaceFilter,
+        gate: Arc<SourceGate>,
     ) -> Self {
         Self {
             tx,
-            run_on_startup: true,
-            interface_index,
-        } // Force run_on_startup to true
+            run_on_startup,
+            filter,
+            links: RwLock::new(HashMap::new()),
+            gate,
+        }
     }
 
     pub async fn run(&self) -> Result<()> {
         // NETLINK_ROUTE is 0
         let mut socket = TokioSocket::new(0)?;
 
-        // RTMGRP_IPV6_IFADDR = 0x100
-        let addr = SocketAddr::new(0, 0x100);
+        // RTMGRP_LINK = 0x1, RTMGRP_IPV4_IFADDR = 0x10, RTMGRP_IPV6_IFADDR = 0x100
+        let addr = SocketAddr::new(0, 0x1 | 0x10 | 0x100);
         socket.socket_mut().bind(&addr)?;
 
         println!(
-            "{} {} Netlink monitor started, listening for IPv6 changes...",
+            "{} {} Netlink monitor started, listening for address changes on {}...",
             Local::now().format("%Y-%m-%d %H:%M:%S"),
-            "[Init]".green()
+            "[Init]".green(),
+            self.filter
         );
 
+        // Indices may have changed while the socket was down
+        *self.links.write().unwrap() = Self::dump_links().await?;
+
         // If configured, fetch existing addresses immediately
         if self.run_on_startup {
             self.fetch_existing_addresses().await?;
@@ -72,10 +246,20 @@ impl NetlinkMonitor {
                     break;
                 }
 
-                if let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(addr_msg)) =
-                    msg.payload
-                {
-                    self.process_message(addr_msg);
+                match msg.payload {
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(addr_msg)) => {
+                        self.process_message(addr_msg, false);
+                    }
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelAddress(addr_msg)) => {
+                        self.process_message(addr_msg, true);
+                    }
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(link_msg)) => {
+                        self.process_link(link_msg, true);
+                    }
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelLink(link_msg)) => {
+                        self.process_link(link_msg, false);
+                    }
+                    _ => {}
                 }
 
                 offset += msg_len;
@@ -84,19 +268,45 @@ impl NetlinkMonitor {
         }
     }
 
-    pub async fn get_current_ipv6(interface_index: Option<u32>) -> Result<Option<Ipv6Addr>> {
+    /// All usable global addresses currently configured on matching interfaces, in kernel order.
+    /// Tentative addresses (Duplicate Address Detection in progress) are not usable yet.
+    pub async fn get_current_addresses(filter: &InterfaceFilter) -> Result<Vec<AddressEvent>> {
+        let links = Self::dump_links().await?;
         let (connection, handle, _) = rtnetlink::new_connection()?;
         tokio::spawn(connection);
 
-        let mut links = handle.address().get().execute();
+        let mut addresses = handle.address().get().execute();
         use futures::stream::TryStreamExt;
 
+        let mut events = Vec::new();
+        while let Some(msg) = addresses.try_next().await.unwrap_or(None) {
+            if let Some(mut event) = Self::extract_address_from_message(msg, false) {
+                event.if_name = links.get(&event.if_index).cloned();
+                if event.is_active()
+                    && !event.has_flag(IFA_F_TENTATIVE)
+                    && filter.matches(event.if_name.as_deref())
+                {
+                    events.push(event);
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn dump_links() -> Result<HashMap<u32, String>> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        let mut links = handle.link().get().execute();
+        use futures::stream::TryStreamExt;
+
+        let mut names = HashMap::new();
         while let Some(msg) = links.try_next().await.unwrap_or(None) {
-            if let Some(addr) = Self::extract_ipv6_from_message(msg, interface_index) {
-                return Ok(Some(addr));
+            if let Some(name) = Self::link_name(&msg) {
+                names.insert(msg.header.index, name);
             }
         }
-        Ok(None)
+        Ok(names)
     }
 
     async fn fetch_existing_addresses(&self) -> Result<()> {
@@ -107,56 +317,224 @@ impl NetlinkMonitor {
         use futures::stream::TryStreamExt;
 
         while let Some(msg) = links.try_next().await.unwrap_or(None) {
-            self.process_message(msg);
+            self.process_message(msg, false);
         }
         Ok(())
     }
 
-    fn process_message(&self, msg: AddressMessage) {
-        if let Some(addr) = Self::extract_ipv6_from_message(msg, self.interface_index) {
-            let _ = self.tx.send(addr);
+    fn process_link(&self, msg: LinkMessage, added: bool) {
+        let index = msg.header.index;
+        let mut links = self.links.write().unwrap();
+        if added {
+            if let Some(name) = Self::link_name(&msg) {
+                if links.get(&index) != Some(&name) {
+                    // A recreated interface (e.g. PPPoE reconnect) comes back under a new index
+                    links.retain(|_, n| *n != name);
+                    links.insert(index, name);
+                }
+            }
+        } else {
+            links.remove(&index);
         }
     }
 
-    fn extract_ipv6_from_message(
-        msg: AddressMessage,
-        interface_index: Option<u32>,
-    ) -> Option<Ipv6Addr> {
-        if msg.header.family != AddressFamily::Inet6 {
-            return None;
-        }
+    fn link_name(msg: &LinkMessage) -> Option<String> {
+        msg.attributes.iter().find_map(|attr| match attr {
+            LinkAttribute::IfName(name) => Some(name.clone()),
+            _ => None,
+        })
+    }
 
-        if let Some(index) = interface_index {
-            if msg.header.index != index {
-                return None;
+    fn process_message(&self, msg: AddressMessage, removed: bool) {
+        if let Some(mut event) = Self::extract_address_from_message(msg, removed) {
+            let cached = self.links.read().unwrap().get(&event.if_index).cloned();
+            event.if_name = cached.or_else(|| interface_name(event.if_index));
+            if self.filter.matches(event.if_name.as_deref()) && self.gate.allows(event.is_ipv4()) {
+                let _ = self.tx.send(event);
             }
         }
+    }
 
-        // Ignore tentative addresses (Duplicate Address Detection in progress)
-        if msg.header.flags.contains(&AddressHeaderFlag::Tentative) {
+    /// Parse an address message. The interface name is left for the caller to resolve.
+    fn extract_address_from_message(msg: AddressMessage, removed: bool) -> Option<AddressEvent> {
+        if msg.header.family != AddressFamily::Inet6 && msg.header.family != AddressFamily::Inet {
             return None;
         }
 
-        // Ignore deprecated addresses (Address is no longer preferred)
-        if msg.header.flags.contains(&AddressHeaderFlag::Deprecated) {
-            return None;
+        // The header only carries the low 8 bits, IFA_FLAGS (if present) has all of them
+        let mut flags = msg
+            .header
+            .flags
+            .iter()
+            .fold(0u32, |acc, f| acc | u8::from(*f) as u32);
+        let mut address = None;
+        let mut local = None;
+        let mut cache_info = None;
+
+        for attr in msg.attributes {
+            match attr {
+                AddressAttribute::Address(addr) => address = Some(addr),
+                AddressAttribute::Local(addr) => local = Some(addr),
+                AddressAttribute::Flags(attr_flags) => {
+                    flags = attr_flags.iter().fold(flags, |acc, f| acc | u32::from(*f));
+                }
+                AddressAttribute::CacheInfo(info) => cache_info = Some(info),
+                _ => {}
+            }
         }
 
-        let mut ipv6_addr = None;
+        // Deprecated addresses are no longer preferred, treat them like a withdrawal
+        let kind = if removed {
+            AddressEventKind::Removed
+        } else if flags & IFA_F_DEPRECATED != 0 {
+            AddressEventKind::Deprecated
+        } else {
+            AddressEventKind::Added
+        };
 
-        for attr in msg.attributes {
-            if let AddressAttribute::Address(IpAddr::V6(addr)) = attr {
-                ipv6_addr = Some(addr);
+        // On point-to-point IPv4 links (e.g. PPPoE) IFA_ADDRESS is the peer, IFA_LOCAL is ours
+        let addr = match (address?, local) {
+            (IpAddr::V4(_), Some(local @ IpAddr::V4(_))) => local,
+            (addr, _) => addr,
+        };
+        let ignored = match addr {
+            IpAddr::V6(v6) => {
+                v6.is_loopback() || v6.is_multicast() || (v6.segments()[0] & 0xffc0) == 0xfe80
+            }
+            IpAddr::V4(v4) => {
+                v4.is_loopback() || v4.is_multicast() || v4.is_link_local() || v4.is_unspecified()
             }
+        };
+        if ignored {
+            return None;
         }
 
-        if let Some(addr) = ipv6_addr {
-            if addr.is_loopback() || addr.is_multicast() || (addr.segments()[0] & 0xffc0) == 0xfe80
-            {
-                return None;
+        Some(AddressEvent {
+            kind,
+            address: addr,
+            prefix_len: msg.header.prefix_len,
+            if_index: msg.header.index,
+            if_name: None,
+            flags,
+            valid_lft: cache_info.map(|info| info.ifa_valid),
+            preferred_lft: cache_info.map(|info| info.ifa_preferred),
+        })
+    }
+}
+
+/// Resolve an interface name from its index via sysfs
+pub fn interface_name(index: u32) -> Option<String> {
+    let entries = std::fs::read_dir("/sys/class/net").ok()?;
+    for entry in entries.flatten() {
+        let path = entry.path().join("ifindex");
+        if let Ok(content) = std::fs::read_to_string(path) {
+            if content.trim().parse::<u32>().ok() == Some(index) {
+                return Some(entry.file_name().to_string_lossy().into_owned());
             }
-            return Some(addr);
         }
-        None
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netlink_packet_route::address::{AddressFlag, AddressHeaderFlag, CacheInfo};
+    use std::str::FromStr;
+
+    fn address_message(addr: &str, prefix_len: u8) -> AddressMessage {
+        let addr = IpAddr::from_str(addr).unwrap();
+        let mut msg = AddressMessage::default();
+        msg.header.family = if addr.is_ipv4() {
+            AddressFamily::Inet
+        } else {
+            AddressFamily::Inet6
+        };
+        msg.header.prefix_len = prefix_len;
+        msg.header.index = 2;
+        msg.attributes.push(AddressAttribute::Address(addr));
+        msg
+    }
+
+    #[test]
+    fn test_extract_event_metadata() {
+        let mut msg = address_message("2001:db8:1::10", 56);
+        let mut info = CacheInfo::default();
+        info.ifa_valid = 7200;
+        info.ifa_preferred = 3600;
+        msg.attributes.push(AddressAttribute::CacheInfo(info));
+        msg.attributes
+            .push(AddressAttribute::Flags(vec![AddressFlag::Managetempaddr]));
+
+        let event = NetlinkMonitor::extract_address_from_message(msg, false).unwrap();
+        assert_eq!(event.kind, AddressEventKind::Added);
+        assert_eq!(event.address, IpAddr::from_str("2001:db8:1::10").unwrap());
+        assert_eq!(event.prefix_len, 56);
+        assert_eq!(event.if_index, 2);
+        assert_eq!(event.flags, 0x100);
+        assert_eq!(event.valid_lft, Some(7200));
+        assert_eq!(event.preferred_lft, Some(3600));
+    }
+
+    #[test]
+    fn test_extract_flags_tentative_and_skips_link_local() {
+        let mut msg = address_message("2001:db8:1::10", 64);
+        msg.header.flags.push(AddressHeaderFlag::Tentative);
+        let event = NetlinkMonitor::extract_address_from_message(msg, false).unwrap();
+        assert!(event.has_flag(IFA_F_TENTATIVE));
+
+        let msg = address_message("fe80::1", 64);
+        assert!(NetlinkMonitor::extract_address_from_message(msg, false).is_none());
+    }
+
+    #[test]
+    fn test_extract_withdrawal_kinds() {
+        let mut msg = address_message("2001:db8:1::10", 64);
+        msg.header.flags.push(AddressHeaderFlag::Deprecated);
+        let event = NetlinkMonitor::extract_address_from_message(msg, false).unwrap();
+        assert_eq!(event.kind, AddressEventKind::Deprecated);
+        assert!(!event.is_active());
+
+        let msg = address_message("2001:db8:1::10", 64);
+        let event = NetlinkMonitor::extract_address_from_message(msg, true).unwrap();
+        assert_eq!(event.kind, AddressEventKind::Removed);
+    }
+
+    #[test]
+    fn test_extract_ipv4_prefers_local_address() {
+        // PPPoE: IFA_ADDRESS holds the peer, IFA_LOCAL our own address
+        let mut msg = address_message("198.51.100.1", 32);
+        msg.attributes.push(AddressAttribute::Local(
+            IpAddr::from_str("203.0.113.7").unwrap(),
+        ));
+        let event = NetlinkMonitor::extract_address_from_message(msg, false).unwrap();
+        assert_eq!(event.address, IpAddr::from_str("203.0.113.7").unwrap());
+        assert!(event.is_ipv4());
+
+        let msg = address_message("169.254.1.1", 16);
+        assert!(NetlinkMonitor::extract_address_from_message(msg, false).is_none());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("eth0", "eth0"));
+        assert!(!glob_match("eth0", "eth1"));
+        assert!(glob_match("ppp*", "ppp0"));
+        assert!(glob_match("ppp*", "ppp"));
+        assert!(!glob_match("ppp*", "eth0"));
+        assert!(glob_match("*wan*", "pppoe-wan"));
+        assert!(glob_match("eth?", "eth1"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn test_interface_filter() {
+        let filter = InterfaceFilter::new(vec!["ppp*".to_string(), "eth0".to_string()]);
+        assert!(filter.matches(Some("ppp1")));
+        assert!(filter.matches(Some("eth0")));
+        assert!(!filter.matches(Some("wg0")));
+        assert!(!filter.matches(None));
+        assert!(InterfaceFilter::default().matches(None));
     }
 }
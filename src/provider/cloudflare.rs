@@ -0,0 +1,370 @@
+use super::{normalize, record_type};
+use crate::config::CloudflareConfig;
+use crate::template;
+use crate::web::{excerpt, HttpStatusError, TemplateVars};
+use anyhow::Result;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Every API v4 response is wrapped like this
+#[derive(Deserialize)]
+struct Envelope<T> {
+    #[serde(default)]
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Zone {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: String,
+    content: String,
+}
+
+struct Api<'a> {
+    client: &'a reqwest::Client,
+    base_url: String,
+    token: String,
+    timeout: Duration,
+}
+
+impl Api<'_> {
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.token)
+            .query(query)
+            .timeout(self.timeout);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        let text = response.text().await.unwrap_or_default();
+        let success_status = (200..300).contains(&status);
+
+        match serde_json::from_str::<Envelope<T>>(&text) {
+            Ok(Envelope {
+                success: true,
+                result: Some(result),
+                ..
+            }) if success_status => Ok(result),
+            Ok(envelope) if !envelope.errors.is_empty() => {
+                let errors = envelope
+                    .errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.code, e.message))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                if success_status {
+                    anyhow::bail!("Cloudflare API error: {}", errors);
+                }
+                // Keep the status so 429 and 5xx are retried
+                Err(HttpStatusError {
+                    status,
+                    body: errors,
+                }
+                .into())
+            }
+            _ if !success_status => Err(HttpStatusError {
+                status,
+                body: excerpt(&text),
+            }
+            .into()),
+            _ => anyhow::bail!("Unexpected Cloudflare response: {}", excerpt(&text)),
+        }
+    }
+
+    /// The zone `fqdn` belongs to: the longest suffix of it that is a zone on the account
+    async fn find_zone(&self, fqdn: &str) -> Result<Zone> {
+        let labels: Vec<&str> = fqdn.split('.').collect();
+        for start in 0..labels.len().saturating_sub(1) {
+            let name = labels[start..].join(".");
+            let zones: Vec<Zone> = self
+                .call(Method::GET, "/zones", &[("name", &name)], None)
+                .await?;
+            if let Some(zone) = zones.into_iter().next() {
+                return Ok(zone);
+            }
+        }
+        anyhow::bail!("No Cloudflare zone found for {}", fqdn)
+    }
+}
+
+/// Find or create the record of `fqdn` and point it at the task's address.
+/// An existing record keeps its TTL and proxy status.
+pub async fn update(
+    config: &CloudflareConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+) -> Result<String> {
+    let base_url = config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL);
+    let api = Api {
+        client,
+        base_url: base_url.trim_end_matches('/').to_string(),
+        token: template::render(&config.api_token, vars)?,
+        timeout,
+    };
+    let fqdn = normalize(&template::render(&config.fqdn, vars)?);
+    let address = vars.combined_ip;
+    let record_type = record_type(address);
+
+    let zone = api.find_zone(&fqdn).await?;
+    let records_path = format!("/zones/{}/dns_records", zone.id);
+    let records: Vec<Record> = api
+        .call(
+            Method::GET,
+            &records_path,
+            &[("type", record_type), ("name", &fqdn)],
+            None,
+        )
+        .await?;
+
+    match records.first() {
+        Some(record) if record.content.parse::<IpAddr>().ok() == Some(address) => Ok(format!(
+            "Cloudflare {} {} already points to {}",
+            record_type, fqdn, address
+        )),
+        Some(record) => {
+            let _: Record = api
+                .call(
+                    Method::PATCH,
+                    &format!("{}/{}", records_path, record.id),
+                    &[],
+                    Some(json!({ "content": address.to_string() })),
+                )
+                .await?;
+            Ok(format!(
+                "Cloudflare {} {} updated from {} to {}",
+                record_type, fqdn, record.content, address
+            ))
+        }
+        None => {
+            let _: Record = api
+                .call(
+                    Method::POST,
+                    &records_path,
+                    &[],
+                    Some(json!({
+                        "type": record_type,
+                        "name": fqdn,
+                        "content": address.to_string(),
+                        "ttl": config.ttl.unwrap_or(1),
+                        "proxied": config.proxied.unwrap_or(false),
+                    })),
+                )
+                .await?;
+            Ok(format!(
+                "Cloudflare {} {} created for {}",
+                record_type, fqdn, address
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, patch};
+    use axum::{Json, Router};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    /// Records of the zone `example.com`, and the changes made to them
+    #[derive(Default)]
+    struct Mock {
+        records: Vec<Value>,
+        changes: Vec<String>,
+    }
+
+    type MockState = Arc<Mutex<Mock>>;
+
+    fn envelope(result: Value) -> Response {
+        Json(json!({ "success": true, "errors": [], "result": result })).into_response()
+    }
+
+    async fn zones(headers: HeaderMap, Query(query): Query<HashMap<String, String>>) -> Response {
+        if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some("Bearer good") {
+            let body = json!({
+                "success": false,
+                "errors": [{ "code": 10000, "message": "Authentication error" }],
+                "result": null,
+            });
+            return (StatusCode::FORBIDDEN, Json(body)).into_response();
+        }
+        match query.get("name").map(String::as_str) {
+            Some("example.com") => envelope(json!([{ "id": "zone1", "name": "example.com" }])),
+            _ => envelope(json!([])),
+        }
+    }
+
+    async fn list_records(
+        State(mock): State<MockState>,
+        Path(zone): Path<String>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        assert_eq!(zone, "zone1");
+        let records: Vec<Value> = mock
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .filter(|r| r["type"] == query["type"] && r["name"] == query["name"])
+            .cloned()
+            .collect();
+        envelope(json!(records))
+    }
+
+    async fn create_record(State(mock): State<MockState>, Json(body): Json<Value>) -> Response {
+        let mut mock = mock.lock().unwrap();
+        let mut record = body.clone();
+        record["id"] = json!(format!("rec{}", mock.records.len() + 1));
+        mock.records.push(record.clone());
+        mock.changes.push(format!("POST {}", body));
+        envelope(record)
+    }
+
+    async fn patch_record(
+        State(mock): State<MockState>,
+        Path((_, id)): Path<(String, String)>,
+        Json(body): Json<Value>,
+    ) -> Response {
+        let mut mock = mock.lock().unwrap();
+        mock.changes.push(format!("PATCH {} {}", id, body));
+        let record = mock.records.iter_mut().find(|r| r["id"] == id).unwrap();
+        record["content"] = body["content"].clone();
+        envelope(record.clone())
+    }
+
+    async fn start_mock() -> (String, MockState) {
+        let mock = MockState::default();
+        let app = Router::new()
+            .route("/zones", get(zones))
+            .route(
+                "/zones/:zone/dns_records",
+                get(list_records).post(create_record),
+            )
+            .route("/zones/:zone/dns_records/:id", patch(patch_record))
+            .with_state(mock.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, mock)
+    }
+
+    fn config(base_url: &str, token: &str) -> CloudflareConfig {
+        CloudflareConfig {
+            api_token: token.to_string(),
+            fqdn: "Home.Example.com.".to_string(),
+            ttl: Some(300),
+            proxied: None,
+            base_url: Some(base_url.to_string()),
+        }
+    }
+
+    fn vars(address: &str) -> TemplateVars {
+        TemplateVars::fixed(IpAddr::from_str(address).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_create_update_and_skip() {
+        let (base_url, mock) = start_mock().await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+        let config = config(&base_url, "good");
+
+        let outcome = update(&config, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            "Cloudflare AAAA home.example.com created for 2001:db8::1"
+        );
+        // The user turned on the proxy in the dashboard meanwhile
+        mock.lock().unwrap().records[0]["proxied"] = json!(true);
+
+        let outcome = update(&config, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap();
+        assert!(outcome.contains("already points to"), "{}", outcome);
+
+        update(&config, &client, timeout, &vars("2001:db8:2::1"))
+            .await
+            .unwrap();
+        // An A record is separate from the AAAA one
+        update(&config, &client, timeout, &vars("192.0.2.1"))
+            .await
+            .unwrap();
+
+        let mock = mock.lock().unwrap();
+        assert_eq!(
+            mock.changes,
+            vec![
+                r#"POST {"content":"2001:db8::1","name":"home.example.com","proxied":false,"ttl":300,"type":"AAAA"}"#,
+                r#"PATCH rec1 {"content":"2001:db8:2::1"}"#,
+                r#"POST {"content":"192.0.2.1","name":"home.example.com","proxied":false,"ttl":300,"type":"A"}"#,
+            ]
+        );
+        assert_eq!(mock.records[0]["proxied"], json!(true));
+        assert_eq!(mock.records[0]["ttl"], json!(300));
+    }
+
+    #[tokio::test]
+    async fn test_api_errors_are_reported() {
+        let (base_url, _) = start_mock().await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+
+        let error = update(
+            &config(&base_url, "bad"),
+            &client,
+            timeout,
+            &vars("2001:db8::1"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "HTTP 403: 10000: Authentication error");
+
+        let mut other_zone = config(&base_url, "good");
+        other_zone.fqdn = "home.example.org".to_string();
+        let error = update(&other_zone, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No Cloudflare zone found for home.example.org"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use super::{check_response, normalize, record_type, relative_name};
+use crate::config::DesecConfig;
+use crate::template;
+use crate::web::TemplateVars;
+use anyhow::Result;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::json;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://desec.io/api/v1";
+const DEFAULT_TTL: u32 = 3600;
+/// Longest `Retry-After` waited for here. Longer rate limits are left to the task's retry policy.
+const MAX_RETRY_AFTER: u64 = 60;
+const MAX_RATE_LIMITED: u32 = 3;
+
+/// Seconds the server asks us to wait, if given as a number
+fn retry_after(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Create or replace the RRset of the record with the task's address
+pub async fn update(
+    config: &DesecConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+) -> Result<String> {
+    let zone = normalize(&config.zone);
+    let name = normalize(&template::render(&config.name, vars)?);
+    let subname = relative_name(&name, &zone)?;
+    let token = template::render(&config.token, vars)?;
+    let address = vars.combined_ip;
+
+    // A bulk PATCH creates the RRset if it does not exist yet, unlike the per-RRset endpoint
+    let base_url = config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL);
+    let url = format!(
+        "{}/domains/{}/rrsets/",
+        base_url.trim_end_matches('/'),
+        zone
+    );
+    let body = json!([{
+        "subname": subname,
+        "type": record_type(address),
+        "ttl": config.ttl.unwrap_or(DEFAULT_TTL),
+        "records": [address.to_string()],
+    }]);
+
+    let mut rate_limited = 0;
+    loop {
+        let response = client
+            .patch(&url)
+            .header("Authorization", format!("Token {}", token))
+            .json(&body)
+            .timeout(timeout)
+            .send()
+            .await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS && rate_limited < MAX_RATE_LIMITED {
+            if let Some(wait) = retry_after(&response).filter(|wait| *wait <= MAX_RETRY_AFTER) {
+                rate_limited += 1;
+                tokio::time::sleep(Duration::from_secs(wait)).await;
+                continue;
+            }
+        }
+        check_response(response).await?;
+        return Ok(format!(
+            "deSEC {} {} set to {}",
+            record_type(address),
+            name,
+            address
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, State};
+    use axum::http::HeaderMap;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::patch;
+    use axum::{Json, Router};
+    use serde_json::Value;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    /// Requests received, and the `Retry-After` of the next responses to throttle
+    #[derive(Default)]
+    struct Mock {
+        requests: Vec<(String, Value)>,
+        throttle: Vec<&'static str>,
+    }
+
+    type MockState = Arc<Mutex<Mock>>;
+
+    async fn patch_rrsets(
+        State(mock): State<MockState>,
+        Path(domain): Path<String>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Response {
+        assert_eq!(headers["authorization"], "Token secret");
+        let mut mock = mock.lock().unwrap();
+        mock.requests.push((domain, body.clone()));
+        if !mock.throttle.is_empty() {
+            let wait = mock.throttle.remove(0);
+            let detail = json!({ "detail": format!("Request was throttled. Expected available in {} seconds.", wait) });
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, wait)],
+                Json(detail),
+            )
+                .into_response();
+        }
+        Json(body).into_response()
+    }
+
+    async fn start_mock(throttle: Vec<&'static str>) -> (String, MockState) {
+        let mock = MockState::new(Mutex::new(Mock {
+            throttle,
+            ..Default::default()
+        }));
+        let app = Router::new()
+            .route("/api/v1/domains/:domain/rrsets/", patch(patch_rrsets))
+            .with_state(mock.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, mock)
+    }
+
+    fn config(base_url: &str) -> DesecConfig {
+        DesecConfig {
+            token: "secret".to_string(),
+            zone: "example.dedyn.io".to_string(),
+            name: "home.example.dedyn.io".to_string(),
+            ttl: None,
+            base_url: Some(base_url.to_string()),
+        }
+    }
+
+    fn vars(address: &str) -> TemplateVars {
+        TemplateVars::fixed(IpAddr::from_str(address).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_update_waits_out_rate_limit() {
+        let (base_url, mock) = start_mock(vec!["1"]).await;
+        let outcome = update(
+            &config(&base_url),
+            &reqwest::Client::new(),
+            Duration::from_secs(5),
+            &vars("2001:db8::1"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            "deSEC AAAA home.example.dedyn.io set to 2001:db8::1"
+        );
+
+        let mock = mock.lock().unwrap();
+        assert_eq!(mock.requests.len(), 2);
+        assert_eq!(mock.requests[1].0, "example.dedyn.io");
+        assert_eq!(
+            mock.requests[1].1,
+            json!([{ "subname": "home", "type": "AAAA", "ttl": 3600, "records": ["2001:db8::1"] }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_rate_limit_is_reported() {
+        let (base_url, mock) = start_mock(vec!["3600"]).await;
+        let error = update(
+            &config(&base_url),
+            &reqwest::Client::new(),
+            Duration::from_secs(5),
+            &vars("2001:db8::1"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "HTTP 429: Request was throttled. Expected available in 3600 seconds."
+        );
+        assert_eq!(mock.lock().unwrap().requests.len(), 1);
+    }
+}
@@ -0,0 +1,319 @@
+use super::{check_response, normalize, record_type, relative_name};
+use crate::config::Dynv6Config;
+use crate::template;
+use crate::web::TemplateVars;
+use anyhow::Result;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://dynv6.com/api/v2";
+
+#[derive(Deserialize)]
+struct Zone {
+    id: u64,
+    #[serde(default, rename = "ipv4address")]
+    ipv4_address: String,
+    #[serde(default, rename = "ipv6prefix")]
+    ipv6_prefix: String,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: u64,
+    #[serde(rename = "type")]
+    rtype: String,
+    name: String,
+    data: String,
+}
+
+struct Api<'a> {
+    client: &'a reqwest::Client,
+    base_url: String,
+    token: String,
+    timeout: Duration,
+}
+
+impl Api<'_> {
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.token)
+            .timeout(self.timeout);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let text = check_response(request.send().await?).await?;
+        serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("Unexpected dynv6 response: {}", e))
+    }
+}
+
+/// Point the record at the task's address, creating it if needed. For the zone itself
+/// this sets the zone's own address.
+pub async fn update(
+    config: &Dynv6Config,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+) -> Result<String> {
+    let zone_name = normalize(&config.zone);
+    let name = normalize(&template::render(&config.name, vars)?);
+    let subname = relative_name(&name, &zone_name)?;
+    let api = Api {
+        client,
+        base_url: config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string(),
+        token: template::render(&config.token, vars)?,
+        timeout,
+    };
+    let address = vars.combined_ip;
+    let record_type = record_type(address);
+    let describe = |action: String| format!("dynv6 {} {} {}", record_type, name, action);
+
+    let zone: Zone = api
+        .call(Method::GET, &format!("/zones/by-name/{}", zone_name), None)
+        .await?;
+
+    if subname.is_empty() {
+        let (field, current) = match address {
+            IpAddr::V4(_) => ("ipv4address", &zone.ipv4_address),
+            IpAddr::V6(_) => ("ipv6prefix", &zone.ipv6_prefix),
+        };
+        if current.parse::<IpAddr>().ok() == Some(address) {
+            return Ok(describe(format!("already points to {}", address)));
+        }
+        let _: serde_json::Value = api
+            .call(
+                Method::PATCH,
+                &format!("/zones/{}", zone.id),
+                Some(json!({ field: address.to_string() })),
+            )
+            .await?;
+        return Ok(describe(format!("set to {}", address)));
+    }
+
+    let records_path = format!("/zones/{}/records", zone.id);
+    let records: Vec<Record> = api.call(Method::GET, &records_path, None).await?;
+    let existing = records
+        .iter()
+        .find(|record| record.rtype == record_type && record.name.eq_ignore_ascii_case(&subname));
+
+    match existing {
+        Some(record) if record.data.parse::<IpAddr>().ok() == Some(address) => {
+            Ok(describe(format!("already points to {}", address)))
+        }
+        Some(record) => {
+            let _: Record = api
+                .call(
+                    Method::PATCH,
+                    &format!("{}/{}", records_path, record.id),
+                    Some(json!({ "data": address.to_string() })),
+                )
+                .await?;
+            Ok(describe(format!(
+                "updated from {} to {}",
+                record.data, address
+            )))
+        }
+        None => {
+            let _: Record = api
+                .call(
+                    Method::POST,
+                    &records_path,
+                    Some(json!({
+                        "type": record_type,
+                        "name": subname,
+                        "data": address.to_string(),
+                    })),
+                )
+                .await?;
+            Ok(describe(format!("created for {}", address)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, patch};
+    use axum::{Json, Router};
+    use serde_json::Value;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    /// The zone `example.dynv6.net` (ID 7), its records, and the changes made to them
+    #[derive(Default)]
+    struct Mock {
+        zone: Value,
+        records: Vec<Value>,
+        changes: Vec<String>,
+    }
+
+    type MockState = Arc<Mutex<Mock>>;
+
+    async fn zone_by_name(
+        State(mock): State<MockState>,
+        Path(name): Path<String>,
+        headers: HeaderMap,
+    ) -> Response {
+        if headers["authorization"] != "Bearer secret" {
+            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+        }
+        if name != "example.dynv6.net" {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "zone not found" })),
+            )
+                .into_response();
+        }
+        Json(mock.lock().unwrap().zone.clone()).into_response()
+    }
+
+    async fn patch_zone(State(mock): State<MockState>, Json(body): Json<Value>) -> Json<Value> {
+        let mut mock = mock.lock().unwrap();
+        mock.changes.push(format!("PATCH zone {}", body));
+        if let Some(prefix) = body.get("ipv6prefix") {
+            mock.zone["ipv6prefix"] = prefix.clone();
+        }
+        Json(mock.zone.clone())
+    }
+
+    async fn list_records(State(mock): State<MockState>) -> Json<Value> {
+        Json(json!(mock.lock().unwrap().records))
+    }
+
+    async fn create_record(State(mock): State<MockState>, Json(body): Json<Value>) -> Json<Value> {
+        let mut mock = mock.lock().unwrap();
+        mock.changes.push(format!("POST {}", body));
+        let mut record = body;
+        record["id"] = json!(mock.records.len() + 1);
+        mock.records.push(record.clone());
+        Json(record)
+    }
+
+    async fn patch_record(
+        State(mock): State<MockState>,
+        Path((_, id)): Path<(u64, u64)>,
+        Json(body): Json<Value>,
+    ) -> Json<Value> {
+        let mut mock = mock.lock().unwrap();
+        mock.changes.push(format!("PATCH {} {}", id, body));
+        let record = mock.records.iter_mut().find(|r| r["id"] == id).unwrap();
+        record["data"] = body["data"].clone();
+        Json(record.clone())
+    }
+
+    async fn start_mock() -> (String, MockState) {
+        let mock = MockState::new(Mutex::new(Mock {
+            zone: json!({ "id": 7, "name": "example.dynv6.net", "ipv4address": "", "ipv6prefix": "2001:db8::1" }),
+            ..Default::default()
+        }));
+        let app = Router::new()
+            .route("/zones/by-name/:name", get(zone_by_name))
+            .route("/zones/:zone", patch(patch_zone))
+            .route(
+                "/zones/:zone/records",
+                get(list_records).post(create_record),
+            )
+            .route("/zones/:zone/records/:id", patch(patch_record))
+            .with_state(mock.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, mock)
+    }
+
+    fn config(base_url: &str, name: &str) -> Dynv6Config {
+        Dynv6Config {
+            token: "secret".to_string(),
+            zone: "example.dynv6.net".to_string(),
+            name: name.to_string(),
+            base_url: Some(base_url.to_string()),
+        }
+    }
+
+    fn vars(address: &str) -> TemplateVars {
+        TemplateVars::fixed(IpAddr::from_str(address).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_records_and_zone_address() {
+        let (base_url, mock) = start_mock().await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+        let home = config(&base_url, "home.example.dynv6.net");
+
+        update(&home, &client, timeout, &vars("2001:db8::10"))
+            .await
+            .unwrap();
+        let outcome = update(&home, &client, timeout, &vars("2001:db8::10"))
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            "dynv6 AAAA home.example.dynv6.net already points to 2001:db8::10"
+        );
+        update(&home, &client, timeout, &vars("2001:db8:1::10"))
+            .await
+            .unwrap();
+
+        let apex = config(&base_url, "example.dynv6.net");
+        let outcome = update(&apex, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap();
+        assert!(
+            outcome.ends_with("already points to 2001:db8::1"),
+            "{}",
+            outcome
+        );
+        update(&apex, &client, timeout, &vars("2001:db8:1::1"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.lock().unwrap().changes,
+            vec![
+                r#"POST {"data":"2001:db8::10","name":"home","type":"AAAA"}"#,
+                r#"PATCH 1 {"data":"2001:db8:1::10"}"#,
+                r#"PATCH zone {"ipv6prefix":"2001:db8:1::1"}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_reported() {
+        let (base_url, _) = start_mock().await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+
+        let mut other_zone = config(&base_url, "home.other.dynv6.net");
+        other_zone.zone = "other.dynv6.net".to_string();
+        let error = update(&other_zone, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "HTTP 404: zone not found");
+
+        let mut bad_token = config(&base_url, "home.example.dynv6.net");
+        bad_token.token = "wrong".to_string();
+        let error = update(&bad_token, &client, timeout, &vars("2001:db8::1"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "HTTP 401: Unauthorized");
+    }
+}
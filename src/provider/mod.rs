@@ -0,0 +1,118 @@
+pub mod cloudflare;
+pub mod desec;
+pub mod dynv6;
+pub mod powerdns;
+pub mod rfc2136;
+
+use crate::config::ProviderConfig;
+use crate::web::{excerpt, HttpStatusError, TemplateVars};
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+/// Point the provider's record at `vars.combined_ip`, and its A record at `ipv4` too for a
+/// task of both families. Returns what was done, for the log.
+pub async fn update(
+    provider: &ProviderConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+    ipv4: Option<Ipv4Addr>,
+) -> Result<String> {
+    // One UPDATE message covers both RRsets
+    if let ProviderConfig::Rfc2136(config) = provider {
+        return rfc2136::update(config, timeout, vars, ipv4).await;
+    }
+    let mut outcomes = vec![update_record(provider, client, timeout, vars).await?];
+    if let Some(v4) = ipv4.map(IpAddr::V4).filter(|v4| *v4 != vars.combined_ip) {
+        let vars = TemplateVars {
+            combined_ip: v4,
+            ..vars.clone()
+        };
+        outcomes.push(update_record(provider, client, timeout, &vars).await?);
+    }
+    Ok(outcomes.join("; "))
+}
+
+/// Point the record of the type matching `vars.combined_ip` at it
+async fn update_record(
+    provider: &ProviderConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+) -> Result<String> {
+    match provider {
+        ProviderConfig::Cloudflare(config) => {
+            cloudflare::update(config, client, timeout, vars).await
+        }
+        ProviderConfig::Rfc2136(config) => rfc2136::update(config, timeout, vars, None).await,
+        ProviderConfig::Desec(config) => desec::update(config, client, timeout, vars).await,
+        ProviderConfig::Dynv6(config) => dynv6::update(config, client, timeout, vars).await,
+        ProviderConfig::PowerDns(config) => powerdns::update(config, client, timeout, vars).await,
+    }
+}
+
+/// DNS record type holding `address`
+pub fn record_type(address: IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+/// Lowercase domain name without the trailing dot
+pub fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Part of `name` in front of `zone`, empty for the zone itself
+pub fn relative_name(name: &str, zone: &str) -> Result<String> {
+    if name == zone {
+        return Ok(String::new());
+    }
+    match name
+        .strip_suffix(zone)
+        .and_then(|rest| rest.strip_suffix('.'))
+    {
+        Some(rest) if !rest.is_empty() => Ok(rest.to_string()),
+        _ => anyhow::bail!("{} is not in zone {}", name, zone),
+    }
+}
+
+/// Body of a successful response. Other statuses become `HttpStatusError`, with the
+/// message of a JSON error body such as `{"error": "..."}` if there is one.
+pub async fn check_response(response: reqwest::Response) -> Result<String> {
+    let status = response.status().as_u16();
+    let text = response.text().await.unwrap_or_default();
+    if (200..300).contains(&status) {
+        return Ok(text);
+    }
+    let message = serde_json::from_str::<serde_json::Value>(&text)
+        .ok()
+        .and_then(|body| {
+            ["error", "detail", "message"]
+                .iter()
+                .find_map(|key| body[key].as_str().map(str::to_string))
+        });
+    Err(HttpStatusError {
+        status,
+        body: message.unwrap_or_else(|| excerpt(&text)),
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_name() {
+        assert_eq!(
+            relative_name("home.example.com", "example.com").unwrap(),
+            "home"
+        );
+        assert_eq!(relative_name("example.com", "example.com").unwrap(), "");
+        assert!(relative_name("home.badexample.com", "example.com").is_err());
+        assert!(relative_name("example.org", "example.com").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use super::{check_response, normalize, record_type, relative_name};
+use crate::config::PowerDnsConfig;
+use crate::template;
+use crate::web::TemplateVars;
+use anyhow::Result;
+use serde_json::json;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "http://127.0.0.1:8081";
+const DEFAULT_SERVER_ID: &str = "localhost";
+const DEFAULT_TTL: u32 = 300;
+
+/// Replace the RRset of the record with the task's address
+pub async fn update(
+    config: &PowerDnsConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+    vars: &TemplateVars,
+) -> Result<String> {
+    let zone = normalize(&config.zone);
+    let name = normalize(&template::render(&config.name, vars)?);
+    relative_name(&name, &zone)?;
+    let address = vars.combined_ip;
+
+    // The API wants fully qualified names, trailing dot included
+    let url = format!(
+        "{}/api/v1/servers/{}/zones/{}.",
+        config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/'),
+        config.server_id.as_deref().unwrap_or(DEFAULT_SERVER_ID),
+        zone
+    );
+    let body = json!({
+        "rrsets": [{
+            "name": format!("{}.", name),
+            "type": record_type(address),
+            "ttl": config.ttl.unwrap_or(DEFAULT_TTL),
+            "changetype": "REPLACE",
+            "records": [{ "content": address.to_string(), "disabled": false }],
+        }]
+    });
+
+    let response = client
+        .patch(&url)
+        .header("X-API-Key", template::render(&config.api_key, vars)?)
+        .json(&body)
+        .timeout(timeout)
+        .send()
+        .await?;
+    check_response(response).await?;
+    Ok(format!(
+        "PowerDNS {} {} set to {}",
+        record_type(address),
+        name,
+        address
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::patch;
+    use axum::{Json, Router};
+    use serde_json::Value;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<Value>>>;
+
+    async fn patch_zone(
+        State(requests): State<Requests>,
+        Path((server, zone)): Path<(String, String)>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Response {
+        if headers["x-api-key"] != "secret" {
+            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+        }
+        if server != "localhost" || zone != "example.com." {
+            let error = json!({ "error": format!("Could not find domain '{}'", zone) });
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        requests.lock().unwrap().push(body);
+        StatusCode::NO_CONTENT.into_response()
+    }
+
+    async fn start_mock() -> (String, Requests) {
+        let requests = Requests::default();
+        let app = Router::new()
+            .route("/api/v1/servers/:server/zones/:zone", patch(patch_zone))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, requests)
+    }
+
+    fn config(base_url: &str) -> PowerDnsConfig {
+        PowerDnsConfig {
+            api_key: "secret".to_string(),
+            zone: "example.com".to_string(),
+            name: "home.example.com.".to_string(),
+            ttl: Some(60),
+            server_id: None,
+            base_url: Some(format!("{}/", base_url)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replace_rrset() {
+        let (base_url, requests) = start_mock().await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+        let vars = TemplateVars::fixed(IpAddr::from_str("192.0.2.1").unwrap());
+
+        let outcome = update(&config(&base_url), &client, timeout, &vars)
+            .await
+            .unwrap();
+        assert_eq!(outcome, "PowerDNS A home.example.com set to 192.0.2.1");
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            json!({ "rrsets": [{
+                "name": "home.example.com.",
+                "type": "A",
+                "ttl": 60,
+                "changetype": "REPLACE",
+                "records": [{ "content": "192.0.2.1", "disabled": false }],
+            }] })
+        );
+
+        let mut other_zone = config(&base_url);
+        other_zone.zone = "example.org".to_string();
+        other_zone.name = "example.org".to_string();
+        let error = update(&other_zone, &client, timeout, &vars)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "HTTP 404: Could not find domain 'example.org.'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_replaces_both_rrsets() {
+        let (base_url, requests) = start_mock().await;
+        let vars = TemplateVars::fixed(IpAddr::from_str("2001:db8::1").unwrap());
+        let provider = crate::config::ProviderConfig::PowerDns(config(&base_url));
+
+        let outcome = super::super::update(
+            &provider,
+            &reqwest::Client::new(),
+            Duration::from_secs(5),
+            &vars,
+            Some(std::net::Ipv4Addr::new(192, 0, 2, 1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            "PowerDNS AAAA home.example.com set to 2001:db8::1; \
+             PowerDNS A home.example.com set to 192.0.2.1"
+        );
+        let requests = requests.lock().unwrap();
+        let types: Vec<&Value> = requests.iter().map(|r| &r["rrsets"][0]["type"]).collect();
+        assert_eq!(types, vec!["AAAA", "A"]);
+        assert_eq!(
+            requests[1]["rrsets"][0]["records"][0]["content"],
+            "192.0.2.1"
+        );
+    }
+}
@@ -0,0 +1,991 @@
+use super::{normalize, record_type, relative_name};
+use crate::config::{DnsProtocol, Prerequisite, Rfc2136Config, TsigAlgorithm, TsigKey};
+use crate::retry::random_fraction;
+use crate::template;
+use crate::web::TemplateVars;
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+const TYPE_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5 << 11;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_TRUNCATED: u16 = 1 << 9;
+/// Allowed clock difference between us and the server, in seconds
+const FUDGE: u16 = 300;
+
+/// A resource record. In UPDATE messages the class says what to do with it (RFC 2136 section 2.4-2.5).
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Record {
+    fn empty(name: &str, rtype: u16, class: u16) -> Self {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl: 0,
+            rdata: Vec::new(),
+        }
+    }
+}
+
+/// The TSIG record closing a message (RFC 8945 section 4.2)
+#[derive(Debug)]
+struct TsigRecord {
+    /// Offset of the record, i.e. length of the signed part of the message
+    start: usize,
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    mac: Vec<u8>,
+    error: u16,
+    other: Vec<u8>,
+}
+
+/// Header, zone and TSIG record of an UPDATE message
+#[derive(Debug)]
+struct Message {
+    id: u16,
+    flags: u16,
+    zone: Option<String>,
+    tsig: Option<TsigRecord>,
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Append `name` in wire format, uncompressed, which is also the canonical form TSIG needs
+fn push_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    let name = name.trim_end_matches('.');
+    let mut length = 1;
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                anyhow::bail!("Invalid domain name {:?}", name);
+            }
+            length += label.len() + 1;
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    if length > 255 {
+        anyhow::bail!("Domain name {:?} is too long", name);
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn push_record(buf: &mut Vec<u8>, record: &Record) -> Result<()> {
+    push_name(buf, &record.name)?;
+    push_u16(buf, record.rtype);
+    push_u16(buf, record.class);
+    push_u32(buf, record.ttl);
+    push_u16(buf, record.rdata.len() as u16);
+    buf.extend_from_slice(&record.rdata);
+    Ok(())
+}
+
+/// Wire format of an UPDATE message without TSIG
+fn encode(
+    id: u16,
+    flags: u16,
+    zone: &str,
+    prerequisites: &[Record],
+    updates: &[Record],
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(512);
+    push_u16(&mut buf, id);
+    push_u16(&mut buf, flags);
+    for count in [1, prerequisites.len(), updates.len(), 0] {
+        push_u16(&mut buf, count as u16);
+    }
+    push_name(&mut buf, zone)?;
+    push_u16(&mut buf, TYPE_SOA);
+    push_u16(&mut buf, CLASS_IN);
+    for record in prerequisites.iter().chain(updates) {
+        push_record(&mut buf, record)?;
+    }
+    Ok(buf)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .context("Truncated DNS message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// Read a possibly compressed name, lowercased
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        // Bounds the walk for pointers that form a loop
+        let mut jumps = 0;
+        loop {
+            let len = *self.buf.get(pos).context("Truncated DNS message")? as usize;
+            match len {
+                0 => {
+                    self.pos = end.unwrap_or(pos + 1);
+                    return Ok(labels.join("."));
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self.buf.get(pos + 1).context("Truncated DNS message")? as usize;
+                    end.get_or_insert(pos + 2);
+                    jumps += 1;
+                    if jumps > 128 {
+                        anyhow::bail!("DNS name compression loop");
+                    }
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                len => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + len)
+                        .context("Truncated DNS message")?;
+                    labels.push(String::from_utf8_lossy(label).to_lowercase());
+                    pos += len + 1;
+                }
+            }
+        }
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let rdata = self.take(len)?.to_vec();
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+fn parse(buf: &[u8]) -> Result<Message> {
+    let mut reader = Reader { buf, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+    let mut zone = None;
+    for _ in 0..counts[0] {
+        zone = Some(reader.name()?);
+        reader.take(4)?;
+    }
+    for _ in 0..u32::from(counts[1]) + u32::from(counts[2]) {
+        reader.record()?;
+    }
+
+    let mut tsig = None;
+    for index in 0..counts[3] {
+        let start = reader.pos;
+        let record = reader.record()?;
+        if record.rtype != TYPE_TSIG {
+            continue;
+        }
+        if index + 1 != counts[3] {
+            anyhow::bail!("TSIG record is not the last one of the message");
+        }
+        let mut rdata = Reader {
+            buf: &record.rdata,
+            pos: 0,
+        };
+        let algorithm = rdata.name()?;
+        let time = rdata.take(6)?;
+        let time_signed = time.iter().fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+        let _fudge = rdata.u16()?;
+        let mac_len = rdata.u16()? as usize;
+        let mac = rdata.take(mac_len)?.to_vec();
+        let _original_id = rdata.u16()?;
+        let error = rdata.u16()?;
+        let other_len = rdata.u16()? as usize;
+        let other = rdata.take(other_len)?.to_vec();
+        tsig = Some(TsigRecord {
+            start,
+            key_name: record.name,
+            algorithm,
+            time_signed,
+            mac,
+            error,
+            other,
+        });
+    }
+
+    Ok(Message {
+        id,
+        flags,
+        zone,
+        tsig,
+    })
+}
+
+fn algorithm_name(algorithm: TsigAlgorithm) -> &'static str {
+    match algorithm {
+        TsigAlgorithm::HmacSha256 => "hmac-sha256",
+        TsigAlgorithm::HmacSha512 => "hmac-sha512",
+    }
+}
+
+/// A TSIG key ready to sign and verify messages
+struct Tsig {
+    name: String,
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl Tsig {
+    fn new(key: &TsigKey, vars: &TemplateVars) -> Result<Self> {
+        let secret = template::render(&key.secret, vars)?;
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret.trim())
+            .map_err(|e| anyhow::anyhow!("Invalid TSIG secret of key {}: {}", key.name, e))?;
+        Ok(Tsig {
+            name: key.name.trim_end_matches('.').to_lowercase(),
+            algorithm: key.algorithm,
+            secret,
+        })
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Data the MAC covers (RFC 8945 section 4.3): the MAC of the request when signing a
+    /// response, the message without its TSIG record, and the TSIG variables
+    fn signed_data(
+        &self,
+        request_mac: Option<&[u8]>,
+        message: &[u8],
+        time_signed: u64,
+        error: u16,
+        other: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(message.len() + 128);
+        if let Some(request_mac) = request_mac {
+            push_u16(&mut data, request_mac.len() as u16);
+            data.extend_from_slice(request_mac);
+        }
+        data.extend_from_slice(message);
+        push_name(&mut data, &self.name)?;
+        push_u16(&mut data, CLASS_ANY);
+        push_u32(&mut data, 0);
+        push_name(&mut data, algorithm_name(self.algorithm))?;
+        data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        push_u16(&mut data, FUDGE);
+        push_u16(&mut data, error);
+        push_u16(&mut data, other.len() as u16);
+        data.extend_from_slice(other);
+        Ok(data)
+    }
+
+    /// Append a TSIG record to `message`, returning its MAC
+    fn sign(
+        &self,
+        message: &mut Vec<u8>,
+        request_mac: Option<&[u8]>,
+        time_signed: u64,
+        error: u16,
+    ) -> Result<Vec<u8>> {
+        let mac = if error == 0 {
+            self.mac(&self.signed_data(request_mac, message, time_signed, error, &[])?)
+        } else {
+            // Errors about the key itself are sent unsigned
+            Vec::new()
+        };
+
+        let mut rdata = Vec::new();
+        push_name(&mut rdata, algorithm_name(self.algorithm))?;
+        rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        push_u16(&mut rdata, FUDGE);
+        push_u16(&mut rdata, mac.len() as u16);
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&message[..2]);
+        push_u16(&mut rdata, error);
+        push_u16(&mut rdata, 0);
+        push_record(
+            message,
+            &Record {
+                name: self.name.clone(),
+                rtype: TYPE_TSIG,
+                class: CLASS_ANY,
+                ttl: 0,
+                rdata,
+            },
+        )?;
+
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        Ok(mac)
+    }
+
+    /// Check the TSIG record of `message`, parsed as `parsed`
+    fn verify(&self, message: &[u8], parsed: &Message, request_mac: Option<&[u8]>) -> Result<()> {
+        let tsig = parsed.tsig.as_ref().context("Message is not signed")?;
+        if tsig.key_name != self.name || tsig.algorithm != algorithm_name(self.algorithm) {
+            anyhow::bail!(
+                "Message is signed with key {} ({}) instead of {}",
+                tsig.key_name,
+                tsig.algorithm,
+                self.name
+            );
+        }
+        // The signed message has the original ID and does not count the TSIG record
+        let mut unsigned = message[..tsig.start].to_vec();
+        let additional = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+        unsigned[10..12].copy_from_slice(&additional.to_be_bytes());
+
+        let data = self.signed_data(
+            request_mac,
+            &unsigned,
+            tsig.time_signed,
+            tsig.error,
+            &tsig.other,
+        )?;
+        let expected = self.mac(&data);
+        let difference = expected
+            .iter()
+            .zip(&tsig.mac)
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if expected.len() != tsig.mac.len() || difference != 0 {
+            anyhow::bail!("Invalid TSIG signature");
+        }
+        if Utc::now().timestamp().abs_diff(tsig.time_signed as i64) > FUDGE as u64 {
+            anyhow::bail!("TSIG time is off by more than {}s", FUDGE);
+        }
+        Ok(())
+    }
+}
+
+/// The server answered the UPDATE with an error RCODE
+#[derive(Debug)]
+pub struct UpdateRejected {
+    pub server: String,
+    pub rcode: u16,
+    /// RCODE name, with the TSIG error and what it means if there is one
+    pub reason: String,
+}
+
+impl UpdateRejected {
+    /// SERVFAIL and REFUSED may go away by themselves, e.g. while the server reloads the zone
+    pub fn is_transient(&self) -> bool {
+        matches!(self.rcode, 2 | 5)
+    }
+}
+
+impl std::fmt::Display for UpdateRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} rejected the update: {}", self.server, self.reason)
+    }
+}
+
+impl std::error::Error for UpdateRejected {}
+
+fn rcode_name(rcode: u16) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        16 => "BADSIG".to_string(),
+        17 => "BADKEY".to_string(),
+        18 => "BADTIME".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+/// Record type number from its mnemonic, or from the RFC 3597 form `TYPE65`
+fn parse_type(rtype: &str) -> Result<u16> {
+    let rtype = rtype.trim().to_uppercase();
+    Ok(match rtype.as_str() {
+        "A" => TYPE_A,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => TYPE_SOA,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => TYPE_AAAA,
+        "SRV" => 33,
+        "HTTPS" => 65,
+        "ANY" => TYPE_ANY,
+        other => other
+            .strip_prefix("TYPE")
+            .and_then(|number| number.parse().ok())
+            .with_context(|| format!("Unknown record type {}", rtype))?,
+    })
+}
+
+/// Address of `server`: an IP address or host name, optionally with a port
+async fn resolve(server: &str) -> Result<SocketAddr> {
+    if let Ok(address) = server.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    let addresses: Vec<SocketAddr> = if server.contains(':') {
+        tokio::net::lookup_host(server).await.map(Iterator::collect)
+    } else {
+        tokio::net::lookup_host((server, 53))
+            .await
+            .map(Iterator::collect)
+    }
+    .with_context(|| format!("Cannot resolve DNS server {}", server))?;
+    addresses
+        .first()
+        .copied()
+        .with_context(|| format!("Cannot resolve DNS server {}", server))
+}
+
+async fn exchange_udp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(request).await?;
+    let mut buf = vec![0; 65535];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Stray datagrams, e.g. late answers to an earlier attempt, are not ours
+        if len >= 12 && buf[..2] == request[..2] {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(request);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Send `request` to `server` and wait for the response
+async fn exchange(server: SocketAddr, protocol: DnsProtocol, request: &[u8]) -> Result<Vec<u8>> {
+    if protocol == DnsProtocol::Udp {
+        let response = exchange_udp(server, request).await?;
+        let flags = u16::from_be_bytes([response[2], response[3]]);
+        if flags & FLAG_TRUNCATED == 0 {
+            return Ok(response);
+        }
+    }
+    exchange_tcp(server, request).await
+}
+
+/// Replace the A or AAAA RRset of the record with the task's address in one UPDATE message.
+/// `ipv4` replaces the A RRset in the same message, for a task of both families.
+pub async fn update(
+    config: &Rfc2136Config,
+    timeout: Duration,
+    vars: &TemplateVars,
+    ipv4: Option<Ipv4Addr>,
+) -> Result<String> {
+    let zone = normalize(&config.zone);
+    let name = normalize(&template::render(&config.name, vars)?);
+    relative_name(&name, &zone)?;
+    let mut addresses = vec![vars.combined_ip];
+    addresses.extend(
+        ipv4.map(IpAddr::V4)
+            .filter(|address| *address != vars.combined_ip),
+    );
+
+    let mut prerequisites = Vec::new();
+    for prerequisite in &config.prerequisites {
+        let owner = normalize(&template::render(prerequisite.name(), vars)?);
+        prerequisites.push(match prerequisite {
+            Prerequisite::NameInUse { .. } => Record::empty(&owner, TYPE_ANY, CLASS_ANY),
+            Prerequisite::NameNotInUse { .. } => Record::empty(&owner, TYPE_ANY, CLASS_NONE),
+            Prerequisite::RrsetExists { rtype, .. } => {
+                Record::empty(&owner, parse_type(rtype)?, CLASS_ANY)
+            }
+            Prerequisite::RrsetNotExists { rtype, .. } => {
+                Record::empty(&owner, parse_type(rtype)?, CLASS_NONE)
+            }
+        });
+    }
+    // Delete the whole RRset, then add the one record, so stale addresses go too
+    let mut updates = Vec::new();
+    for address in &addresses {
+        let (rtype, rdata) = match address {
+            IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+        };
+        updates.push(Record::empty(&name, rtype, CLASS_ANY));
+        updates.push(Record {
+            name: name.clone(),
+            rtype,
+            class: CLASS_IN,
+            ttl: config.ttl,
+            rdata,
+        });
+    }
+
+    let id = (random_fraction() * 65536.0) as u16;
+    let mut request = encode(id, OPCODE_UPDATE, &zone, &prerequisites, &updates)?;
+    let tsig = config
+        .tsig
+        .as_ref()
+        .map(|key| Tsig::new(key, vars))
+        .transpose()?;
+    let request_mac = match &tsig {
+        Some(tsig) => Some(tsig.sign(&mut request, None, Utc::now().timestamp() as u64, 0)?),
+        None => None,
+    };
+
+    let server = resolve(&config.server).await?;
+    let response = tokio::time::timeout(timeout, exchange(server, config.protocol, &request))
+        .await
+        .map_err(|e| {
+            anyhow::Error::new(e).context(format!(
+                "No response from {} within {}s",
+                server,
+                timeout.as_secs()
+            ))
+        })?
+        .with_context(|| format!("DNS update via {} failed", server))?;
+
+    let parsed = parse(&response)?;
+    if parsed.id != id
+        || parsed.flags & FLAG_RESPONSE == 0
+        || parsed.flags & (0xf << 11) != OPCODE_UPDATE
+        || parsed.zone.as_ref().is_some_and(|z| *z != zone)
+    {
+        anyhow::bail!("{} did not answer our UPDATE request", server);
+    }
+    let rcode = parsed.flags & 0xf;
+    let tsig_error = parsed.tsig.as_ref().map_or(0, |tsig| tsig.error);
+    if rcode != 0 || tsig_error != 0 {
+        let mut reason = rcode_name(rcode);
+        if tsig_error != 0 {
+            reason = format!("{} ({})", reason, rcode_name(tsig_error));
+        }
+        if (6..=8).contains(&rcode) {
+            reason.push_str(", a prerequisite was not met");
+        }
+        return Err(UpdateRejected {
+            server: server.to_string(),
+            rcode,
+            reason,
+        }
+        .into());
+    }
+    if let Some(tsig) = &tsig {
+        tsig.verify(&response, &parsed, request_mac.as_deref())
+            .with_context(|| format!("Response from {} failed TSIG verification", server))?;
+    }
+
+    let changes: Vec<String> = addresses
+        .iter()
+        .map(|address| format!("{} {} set to {}", record_type(*address), name, address))
+        .collect();
+    Ok(format!("RFC 2136 {} via {}", changes.join(", "), server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TsigKey;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const SECRET: &str = "c2VjcmV0LWtleS1mb3ItdGVzdHM=";
+
+    fn key(secret: &str, algorithm: TsigAlgorithm) -> TsigKey {
+        TsigKey {
+            name: "ddns-key.".to_string(),
+            algorithm,
+            secret: secret.to_string(),
+        }
+    }
+
+    fn vars(address: &str) -> TemplateVars {
+        TemplateVars::fixed(IpAddr::from_str(address).unwrap())
+    }
+
+    fn aaaa(name: &str, address: &str) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype: TYPE_AAAA,
+            class: CLASS_IN,
+            ttl: 60,
+            rdata: Ipv6Addr::from_str(address).unwrap().octets().to_vec(),
+        }
+    }
+
+    /// Authoritative server for `example.com`, applying updates signed with its key
+    struct Zone {
+        key: Tsig,
+        records: Mutex<Vec<Record>>,
+    }
+
+    impl Zone {
+        fn answer(&self, request: &[u8]) -> Vec<u8> {
+            let message = parse(request).unwrap();
+            let (rcode, tsig_error) = match self.key.verify(request, &message, None) {
+                Err(_) => (9, 16),
+                Ok(()) if message.zone.as_deref() != Some("example.com") => (10, 0),
+                Ok(()) => (self.apply(request), 0),
+            };
+            let mut response = encode(
+                message.id,
+                FLAG_RESPONSE | OPCODE_UPDATE | rcode,
+                "example.com",
+                &[],
+                &[],
+            )
+            .unwrap();
+            let request_mac = message.tsig.map(|tsig| tsig.mac);
+            let now = Utc::now().timestamp() as u64;
+            self.key
+                .sign(&mut response, request_mac.as_deref(), now, tsig_error)
+                .unwrap();
+            response
+        }
+
+        fn apply(&self, request: &[u8]) -> u16 {
+            let mut reader = Reader {
+                buf: request,
+                pos: 12,
+            };
+            reader.name().unwrap();
+            reader.take(4).unwrap();
+            let mut read = |count: u16| -> Vec<Record> {
+                (0..count).map(|_| reader.record().unwrap()).collect()
+            };
+            let prerequisites = read(u16::from_be_bytes([request[6], request[7]]));
+            let updates = read(u16::from_be_bytes([request[8], request[9]]));
+
+            let mut records = self.records.lock().unwrap();
+            for prerequisite in &prerequisites {
+                let name_used = records.iter().any(|r| r.name == prerequisite.name);
+                let rrset_exists = records
+                    .iter()
+                    .any(|r| r.name == prerequisite.name && r.rtype == prerequisite.rtype);
+                let failure = match (prerequisite.class, prerequisite.rtype) {
+                    (CLASS_ANY, TYPE_ANY) if !name_used => 3,
+                    (CLASS_NONE, TYPE_ANY) if name_used => 6,
+                    (CLASS_ANY, _) if prerequisite.rtype != TYPE_ANY && !rrset_exists => 8,
+                    (CLASS_NONE, _) if prerequisite.rtype != TYPE_ANY && rrset_exists => 7,
+                    _ => 0,
+                };
+                if failure != 0 {
+                    return failure;
+                }
+            }
+            for update in &updates {
+                match update.class {
+                    CLASS_ANY => {
+                        records.retain(|r| !(r.name == update.name && r.rtype == update.rtype))
+                    }
+                    _ => records.push(update.clone()),
+                }
+            }
+            0
+        }
+    }
+
+    /// Serve `zone` over UDP and TCP on the same port
+    async fn start_server(zone: Arc<Zone>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(address).await.unwrap();
+
+        let udp_zone = zone.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let response = udp_zone.answer(&buf[..len]);
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut request = vec![0; len];
+                stream.read_exact(&mut request).await.unwrap();
+                let response = zone.answer(&request);
+                stream
+                    .write_all(&(response.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn zone(algorithm: TsigAlgorithm, records: Vec<Record>) -> Arc<Zone> {
+        Arc::new(Zone {
+            key: Tsig::new(&key(SECRET, algorithm), &vars("::1")).unwrap(),
+            records: Mutex::new(records),
+        })
+    }
+
+    fn config(server: SocketAddr, algorithm: TsigAlgorithm) -> Rfc2136Config {
+        Rfc2136Config {
+            server: server.to_string(),
+            zone: "Example.com.".to_string(),
+            name: "home.example.com".to_string(),
+            ttl: 120,
+            protocol: DnsProtocol::Udp,
+            tsig: Some(key(SECRET, algorithm)),
+            prerequisites: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_replaces_rrset() {
+        let other = aaaa("nas.example.com", "2001:db8::2");
+        let zone = zone(
+            TsigAlgorithm::HmacSha256,
+            vec![
+                aaaa("home.example.com", "2001:db8::1"),
+                aaaa("home.example.com", "2001:db8::ffff"),
+                other.clone(),
+            ],
+        );
+        let server = start_server(zone.clone()).await;
+        let timeout = Duration::from_secs(5);
+
+        let outcome = update(
+            &config(server, TsigAlgorithm::HmacSha256),
+            timeout,
+            &vars("2001:db8:1::1"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            format!(
+                "RFC 2136 AAAA home.example.com set to 2001:db8:1::1 via {}",
+                server
+            )
+        );
+        let mut expected = aaaa("home.example.com", "2001:db8:1::1");
+        expected.ttl = 120;
+        assert_eq!(*zone.records.lock().unwrap(), vec![other.clone(), expected]);
+
+        let mut tcp = config(server, TsigAlgorithm::HmacSha256);
+        tcp.protocol = DnsProtocol::Tcp;
+        update(&tcp, timeout, &vars("2001:db8:2::1"), None)
+            .await
+            .unwrap();
+        assert_eq!(zone.records.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_update_sets_both_rrsets() {
+        let mut stale_a = aaaa("home.example.com", "::1");
+        stale_a.rtype = TYPE_A;
+        stale_a.rdata = vec![192, 0, 2, 1];
+        let zone = zone(
+            TsigAlgorithm::HmacSha256,
+            vec![aaaa("home.example.com", "2001:db8::1"), stale_a],
+        );
+        let server = start_server(zone.clone()).await;
+
+        let outcome = update(
+            &config(server, TsigAlgorithm::HmacSha256),
+            Duration::from_secs(5),
+            &vars("2001:db8:1::1"),
+            Some(Ipv4Addr::new(203, 0, 113, 7)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            format!(
+                "RFC 2136 AAAA home.example.com set to 2001:db8:1::1, \
+                 A home.example.com set to 203.0.113.7 via {}",
+                server
+            )
+        );
+        let records = zone.records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].rtype, TYPE_A);
+        assert_eq!(records[1].rdata, vec![203, 0, 113, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_updates() {
+        let zone = zone(
+            TsigAlgorithm::HmacSha512,
+            vec![aaaa("home.example.com", "2001:db8::1")],
+        );
+        let server = start_server(zone.clone()).await;
+        let timeout = Duration::from_secs(5);
+
+        let mut wrong_key = config(server, TsigAlgorithm::HmacSha512);
+        wrong_key.tsig = Some(key("b3RoZXI=", TsigAlgorithm::HmacSha512));
+        let error = update(&wrong_key, timeout, &vars("2001:db8:1::1"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("{} rejected the update: NOTAUTH (BADSIG)", server)
+        );
+        assert!(!error
+            .downcast_ref::<UpdateRejected>()
+            .unwrap()
+            .is_transient());
+
+        let mut create_only = config(server, TsigAlgorithm::HmacSha512);
+        create_only.prerequisites = vec![Prerequisite::RrsetNotExists {
+            name: "{{ 'home' }}.example.com".to_string(),
+            rtype: "aaaa".to_string(),
+        }];
+        let error = update(&create_only, timeout, &vars("2001:db8:1::1"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} rejected the update: YXRRSET, a prerequisite was not met",
+                server
+            )
+        );
+        assert_eq!(
+            *zone.records.lock().unwrap(),
+            vec![aaaa("home.example.com", "2001:db8::1")]
+        );
+
+        let mut outside = config(server, TsigAlgorithm::HmacSha512);
+        outside.name = "home.example.org".to_string();
+        let error = update(&outside, timeout, &vars("2001:db8:1::1"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "home.example.org is not in zone example.com"
+        );
+    }
+
+    #[test]
+    fn test_tsig_signature() {
+        let tsig = Tsig::new(&key(SECRET, TsigAlgorithm::HmacSha256), &vars("::1")).unwrap();
+        let mut update = aaaa("home.example.com", "2001:db8::1");
+        update.ttl = 120;
+        let mut message = encode(0x1234, OPCODE_UPDATE, "example.com", &[], &[update]).unwrap();
+        let mac = tsig.sign(&mut message, None, 1_700_000_000, 0).unwrap();
+        // Computed independently from RFC 8945 section 4.3
+        assert_eq!(
+            mac.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "760e6ae3d4e3b1ba2db7e61684c43d713a3c11f329a9159490234dc74fe729b3"
+        );
+
+        let parsed = parse(&message).unwrap();
+        let record = parsed.tsig.as_ref().unwrap();
+        assert_eq!(record.key_name, "ddns-key");
+        assert_eq!(record.time_signed, 1_700_000_000);
+        // Only the clock is off
+        let error = tsig.verify(&message, &parsed, None).unwrap_err();
+        assert!(error.to_string().contains("time is off"), "{}", error);
+    }
+
+    #[test]
+    fn test_names_and_types() {
+        let mut buf = Vec::new();
+        push_name(&mut buf, "a.Example.").unwrap();
+        assert_eq!(buf, b"\x01a\x07Example\x00");
+        assert!(push_name(&mut Vec::new(), "a..b").is_err());
+        assert!(push_name(&mut Vec::new(), &"x".repeat(64)).is_err());
+
+        // Pointer to the name at offset 0
+        let message = b"\x01a\x07example\x00\xc0\x00";
+        let mut reader = Reader {
+            buf: message,
+            pos: 11,
+        };
+        assert_eq!(reader.name().unwrap(), "a.example");
+        assert_eq!(reader.pos, 13);
+        let looping = b"\xc0\x00";
+        assert!(Reader {
+            buf: looping,
+            pos: 0
+        }
+        .name()
+        .is_err());
+
+        assert_eq!(parse_type("aaaa").unwrap(), TYPE_AAAA);
+        assert_eq!(parse_type("TYPE65534").unwrap(), 65534);
+        assert!(parse_type("NOPE").is_err());
+    }
+}
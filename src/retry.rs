@@ -0,0 +1,268 @@
+use crate::config::{RetryPolicy, Task};
+use crate::logging::log_to_web;
+use crate::provider::rfc2136::UpdateRejected;
+use crate::web::{send_webhook, AppState, HttpStatusError, TemplateVars};
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// A failed webhook request waiting for its next attempt
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingRetry {
+    pub task_id: String,
+    pub task_name: String,
+    pub address: IpAddr,
+    /// Attempts made so far
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub next_attempt_at: String,
+    pub last_error: String,
+}
+
+#[derive(Default)]
+struct Runs {
+    /// Bumped every time a task starts a new run, so older runs know they were superseded
+    generations: HashMap<String, u64>,
+    pending: HashMap<String, PendingRetry>,
+}
+
+/// Tracks the latest run of each task and the retries it is waiting on
+#[derive(Clone, Default)]
+pub struct RetryTracker {
+    runs: Arc<Mutex<Runs>>,
+}
+
+impl RetryTracker {
+    /// Start a new run of `task_id`, cancelling any retry of an older run
+    pub fn begin(&self, task_id: &str) -> u64 {
+        let mut runs = self.runs.lock().unwrap();
+        runs.pending.remove(task_id);
+        let generation = runs.generations.entry(task_id.to_string()).or_default();
+        *generation += 1;
+        *generation
+    }
+
+    /// Whether `generation` is still the latest run of `task_id`
+    pub fn is_current(&self, task_id: &str, generation: u64) -> bool {
+        self.runs.lock().unwrap().generations.get(task_id) == Some(&generation)
+    }
+
+    fn schedule(&self, generation: u64, retry: PendingRetry) {
+        let mut runs = self.runs.lock().unwrap();
+        if runs.generations.get(&retry.task_id) == Some(&generation) {
+            runs.pending.insert(retry.task_id.clone(), retry);
+        }
+    }
+
+    fn finish(&self, task_id: &str, generation: u64) {
+        let mut runs = self.runs.lock().unwrap();
+        if runs.generations.get(task_id) == Some(&generation) {
+            runs.pending.remove(task_id);
+        }
+    }
+
+    pub fn pending(&self) -> Vec<PendingRetry> {
+        let mut pending: Vec<PendingRetry> = self
+            .runs
+            .lock()
+            .unwrap()
+            .pending
+            .values()
+            .cloned()
+            .collect();
+        pending.sort_by(|a, b| a.next_attempt_at.cmp(&b.next_attempt_at));
+        pending
+    }
+}
+
+/// A retry was abandoned because a newer run of the same task started
+#[derive(Debug)]
+pub struct Superseded;
+
+impl fmt::Display for Superseded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "superseded by a newer run")
+    }
+}
+
+impl std::error::Error for Superseded {}
+
+/// Whether `error` from `send_webhook` may go away by trying again
+pub fn is_retryable(policy: &RetryPolicy, error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<HttpStatusError>() {
+        return policy.retry_on_status.contains(&e.status);
+    }
+    // DNS updates fail with these instead
+    if let Some(e) = error.downcast_ref::<UpdateRejected>() {
+        return e.is_transient();
+    }
+    if error.is::<tokio::time::error::Elapsed>() || error.is::<std::io::Error>() {
+        return true;
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+        None => false,
+    }
+}
+
+/// Uniform value in 0..1, good enough to spread retries apart
+pub fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(Utc::now().timestamp_nanos_opt().unwrap_or_default() as u128);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Send the webhook of `task` for run `generation`, retrying according to its policy
+pub async fn send_with_retry(
+    state: &AppState,
+    task: &Task,
+    vars: &TemplateVars,
+    generation: u64,
+    source: &str,
+    log_limit: usize,
+) -> Result<String> {
+    let policy = task.retry.clone().unwrap_or(RetryPolicy {
+        max_attempts: 1,
+        ..Default::default()
+    });
+    let mut attempt = 1;
+    loop {
+        let error = match send_webhook(&state.http, task, vars).await {
+            Ok(outcome) => {
+                state.retries.finish(&task.id, generation);
+                return Ok(outcome);
+            }
+            Err(e) => e,
+        };
+        if attempt >= policy.max_attempts || !is_retryable(&policy, &error) {
+            state.retries.finish(&task.id, generation);
+            return Err(error);
+        }
+
+        let delay = policy.delay(attempt, random_fraction());
+        let next_attempt_at = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+        state.retries.schedule(
+            generation,
+            PendingRetry {
+                task_id: task.id.clone(),
+                task_name: task.name.clone(),
+                address: vars.combined_ip,
+                attempt,
+                max_attempts: policy.max_attempts,
+                next_attempt_at: next_attempt_at.to_rfc3339(),
+                last_error: error.to_string(),
+            },
+        );
+        let log_msg = format!(
+            "Task [{}]: Attempt {}/{} failed: {}, retrying in {:.1}s",
+            task.name,
+            attempt,
+            policy.max_attempts,
+            error,
+            delay.as_secs_f64()
+        );
+        log_to_web(
+            &state.log_tx,
+            &state.recent_logs,
+            source,
+            "warning",
+            &log_msg,
+            log_limit,
+        )
+        .await;
+
+        tokio::time::sleep(delay).await;
+        if !state.retries.is_current(&task.id, generation) {
+            return Err(Superseded.into());
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_is_capped_and_jittered() {
+        let policy = RetryPolicy {
+            backoff_base: 5,
+            backoff_cap: 60,
+            jitter: 0.2,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1, 0.5), Duration::from_secs(5));
+        assert_eq!(policy.delay(2, 0.5), Duration::from_secs(10));
+        assert_eq!(policy.delay(4, 0.5), Duration::from_secs(40));
+        assert_eq!(policy.delay(5, 0.5), Duration::from_secs(60));
+        assert_eq!(policy.delay(40, 0.5), Duration::from_secs(60));
+        assert_eq!(policy.delay(1, 0.0), Duration::from_secs(4));
+        assert_eq!(policy.delay(1, 1.0), Duration::from_secs(6));
+    }
+
+    fn http_error(status: u16) -> anyhow::Error {
+        HttpStatusError {
+            status,
+            body: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let policy = RetryPolicy::default();
+        assert!(is_retryable(&policy, &http_error(503)));
+        assert!(is_retryable(&policy, &http_error(429)));
+        assert!(!is_retryable(&policy, &http_error(404)));
+        assert!(!is_retryable(&policy, &anyhow::anyhow!("bad template")));
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(is_retryable(
+            &policy,
+            &anyhow::Error::new(refused).context("DNS update failed")
+        ));
+        let rejected = |rcode, reason: &str| {
+            anyhow::Error::new(UpdateRejected {
+                server: "ns1.example.com".to_string(),
+                rcode,
+                reason: reason.to_string(),
+            })
+        };
+        assert!(is_retryable(&policy, &rejected(2, "SERVFAIL")));
+        assert!(is_retryable(&policy, &rejected(5, "REFUSED")));
+        assert!(!is_retryable(&policy, &rejected(9, "NOTAUTH (BADSIG)")));
+    }
+
+    #[test]
+    fn test_newer_run_cancels_pending_retry() {
+        let tracker = RetryTracker::default();
+        let first = tracker.begin("t1");
+        tracker.schedule(
+            first,
+            PendingRetry {
+                task_id: "t1".to_string(),
+                task_name: "Test".to_string(),
+                address: "2001:db8:1::1".parse().unwrap(),
+                attempt: 1,
+                max_attempts: 3,
+                next_attempt_at: Utc::now().to_rfc3339(),
+                last_error: "HTTP 503".to_string(),
+            },
+        );
+        assert_eq!(tracker.pending().len(), 1);
+
+        let second = tracker.begin("t1");
+        assert!(tracker.pending().is_empty());
+        assert!(!tracker.is_current("t1", first));
+        assert!(tracker.is_current("t1", second));
+        // A late finish of the old run leaves the new one alone
+        tracker.finish("t1", first);
+        assert!(tracker.is_current("t1", second));
+    }
+}
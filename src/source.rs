@@ -0,0 +1,517 @@
+use crate::config::{AppConfig, HttpOptions, SourceConfig};
+use crate::http::HttpClients;
+use crate::logging::log_to_web;
+use crate::netlink::{AddressEvent, AddressEventKind, InterfaceFilter, NetlinkMonitor};
+use crate::web::AppState;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Somewhere the current addresses can be detected
+pub trait PrefixSource: Send + Sync {
+    /// Short description for logs, e.g. `http (https://api64.ipify.org)`
+    fn name(&self) -> String;
+
+    /// Addresses the source currently reports
+    fn detect(&self) -> BoxFuture<'_, Result<Vec<AddressEvent>>>;
+
+    /// Whether changes are pushed as they happen (netlink) instead of found by polling
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Interface addresses from the kernel. Changes arrive through `NetlinkMonitor`.
+pub struct NetlinkSource {
+    filter: InterfaceFilter,
+}
+
+impl PrefixSource for NetlinkSource {
+    fn name(&self) -> String {
+        "netlink".to_string()
+    }
+
+    fn detect(&self) -> BoxFuture<'_, Result<Vec<AddressEvent>>> {
+        Box::pin(NetlinkMonitor::get_current_addresses(&self.filter))
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+/// Seconds an echo endpoint gets to answer unless its `http` options say otherwise
+const ECHO_TIMEOUT: u64 = 10;
+
+/// A "what is my IP" endpoint answering with the address as text or JSON
+pub struct HttpEchoSource {
+    url: String,
+    json_path: Option<String>,
+    interface: String,
+    options: HttpOptions,
+    clients: HttpClients,
+}
+
+impl PrefixSource for HttpEchoSource {
+    fn name(&self) -> String {
+        format!("http ({})", self.url)
+    }
+
+    fn detect(&self) -> BoxFuture<'_, Result<Vec<AddressEvent>>> {
+        Box::pin(async move {
+            let res = self
+                .clients
+                .get(&self.options)?
+                .get(&self.url)
+                .timeout(Duration::from_secs(
+                    self.options.timeout.unwrap_or(ECHO_TIMEOUT),
+                ))
+                .send()
+                .await?;
+            if !res.status().is_success() {
+                anyhow::bail!("HTTP {}", res.status().as_u16());
+            }
+            let body = res.text().await?;
+            let text = match &self.json_path {
+                Some(path) => {
+                    let json: serde_json::Value = serde_json::from_str(&body)?;
+                    match json_lookup(&json, path) {
+                        Some(serde_json::Value::String(s)) => s.clone(),
+                        _ => anyhow::bail!("No string at JSON path '{}'", path),
+                    }
+                }
+                None => body,
+            };
+            Ok(parse_addresses(&text, &self.interface))
+        })
+    }
+}
+
+/// A shell command printing one address per line
+pub struct CommandSource {
+    command: String,
+    interface: String,
+}
+
+impl PrefixSource for CommandSource {
+    fn name(&self) -> String {
+        format!("command ({})", self.command)
+    }
+
+    fn detect(&self) -> BoxFuture<'_, Result<Vec<AddressEvent>>> {
+        Box::pin(async move {
+            let output = tokio::time::timeout(
+                Duration::from_secs(30),
+                tokio::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&self.command)
+                    .kill_on_drop(true)
+                    .output(),
+            )
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out"))??;
+            if !output.status.success() {
+                anyhow::bail!("Exited with {}", output.status);
+            }
+            Ok(parse_addresses(
+                &String::from_utf8_lossy(&output.stdout),
+                &self.interface,
+            ))
+        })
+    }
+}
+
+pub fn build_source(
+    config: &SourceConfig,
+    filter: &InterfaceFilter,
+    clients: &HttpClients,
+) -> Box<dyn PrefixSource> {
+    match config {
+        SourceConfig::Netlink => Box::new(NetlinkSource {
+            filter: filter.clone(),
+        }),
+        SourceConfig::Http {
+            url,
+            json_path,
+            interface,
+            http,
+        } => Box::new(HttpEchoSource {
+            url: url.clone(),
+            json_path: json_path.clone(),
+            interface: interface.clone(),
+            options: http.clone().unwrap_or_default(),
+            clients: clients.clone(),
+        }),
+        SourceConfig::Command { command, interface } => Box::new(CommandSource {
+            command: command.clone(),
+            interface: interface.clone(),
+        }),
+    }
+}
+
+/// Follow a dotted path such as `data.addresses.0.ip` (a leading `$.` is allowed)
+pub fn json_lookup<'a>(json: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let path = path.strip_prefix("$.").unwrap_or(path);
+    path.split('.')
+        .filter(|segment| !segment.is_empty())
+        .try_fold(json, |value, segment| match value {
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment),
+        })
+}
+
+/// Every whitespace-separated token that is an IP address, reported on `interface`
+fn parse_addresses(text: &str, interface: &str) -> Vec<AddressEvent> {
+    text.split_whitespace()
+        .filter_map(|token| token.parse::<IpAddr>().ok())
+        .map(|address| AddressEvent {
+            kind: AddressEventKind::Added,
+            address,
+            prefix_len: if address.is_ipv4() { 32 } else { 128 },
+            if_index: 0,
+            if_name: Some(interface.to_string()),
+            flags: 0,
+            valid_lft: None,
+            preferred_lft: None,
+        })
+        .collect()
+}
+
+/// Whether netlink events of each family are currently used
+pub struct SourceGate {
+    ipv6: AtomicBool,
+    ipv4: AtomicBool,
+}
+
+impl Default for SourceGate {
+    fn default() -> Self {
+        Self {
+            ipv6: AtomicBool::new(true),
+            ipv4: AtomicBool::new(true),
+        }
+    }
+}
+
+impl SourceGate {
+    pub fn allows(&self, ipv4: bool) -> bool {
+        if ipv4 {
+            self.ipv4.load(Ordering::Relaxed)
+        } else {
+            self.ipv6.load(Ordering::Relaxed)
+        }
+    }
+
+    fn set(&self, ipv4: bool, open: bool) {
+        if ipv4 {
+            self.ipv4.store(open, Ordering::Relaxed);
+        } else {
+            self.ipv6.store(open, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Addresses chosen for one family, and the source they came from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selection {
+    /// Index of the winning source in `sources`
+    pub source: Option<usize>,
+    pub live: bool,
+    pub name: Option<String>,
+    pub addresses: Vec<AddressEvent>,
+}
+
+/// Ask the sources in priority order until each family has an address.
+/// Returns the selections for IPv6 and IPv4, plus the errors met along the way.
+pub async fn select_addresses(
+    config: &AppConfig,
+    filter: &InterfaceFilter,
+    clients: &HttpClients,
+) -> (Selection, Selection, Vec<String>) {
+    let mut ipv6 = Selection::default();
+    let mut ipv4 = Selection::default();
+    let mut errors = Vec::new();
+
+    for (index, source_config) in config.effective_sources().iter().enumerate() {
+        if ipv6.source.is_some() && ipv4.source.is_some() {
+            break;
+        }
+        let source = build_source(source_config, filter, clients);
+        let events = match source.detect().await {
+            Ok(events) => events,
+            Err(e) => {
+                errors.push(format!("{}: {}", source.name(), e));
+                continue;
+            }
+        };
+        for (selection, want_ipv4) in [(&mut ipv6, false), (&mut ipv4, true)] {
+            if selection.source.is_some() {
+                continue;
+            }
+            let addresses: Vec<AddressEvent> = events
+                .iter()
+                .filter(|e| {
+                    e.is_ipv4() == want_ipv4 && config.address_filter.reject_reason(e).is_none()
+                })
+                .cloned()
+                .collect();
+            if !addresses.is_empty() {
+                *selection = Selection {
+                    source: Some(index),
+                    live: source.is_live(),
+                    name: Some(source.name()),
+                    addresses,
+                };
+            }
+        }
+    }
+    (ipv6, ipv4, errors)
+}
+
+/// What the manager last reported
+#[derive(Default)]
+struct Announced {
+    /// Addresses announced on behalf of polled sources
+    addresses: Vec<AddressEvent>,
+    ipv6_source: Option<String>,
+    ipv4_source: Option<String>,
+}
+
+/// Polls the configured sources and feeds addresses from the polled ones into the netlink channel
+pub struct SourceManager {
+    tx: broadcast::Sender<AddressEvent>,
+    gate: Arc<SourceGate>,
+    announced: Mutex<Announced>,
+}
+
+impl SourceManager {
+    pub fn new(tx: broadcast::Sender<AddressEvent>, gate: Arc<SourceGate>) -> Self {
+        Self {
+            tx,
+            gate,
+            announced: Mutex::new(Announced::default()),
+        }
+    }
+
+    /// Take over the selection made at startup, whose addresses were already processed there
+    pub fn prime(&self, config: &AppConfig, ipv6: &Selection, ipv4: &Selection) -> Vec<String> {
+        self.update(config, ipv6, ipv4, false)
+    }
+
+    /// Poll the sources every `source_poll_interval`, starting one interval after `prime`
+    pub async fn run(&self, state: AppState, filter: InterfaceFilter) {
+        loop {
+            let interval = state
+                .config_manager
+                .config
+                .read()
+                .await
+                .source_poll_interval;
+            tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+
+            let config = state.config_manager.config.read().await.clone();
+            let (ipv6, ipv4, errors) = select_addresses(&config, &filter, &state.http).await;
+            for error in errors {
+                let msg = format!("Source failed: {}", error);
+                log_to_web(
+                    &state.log_tx,
+                    &state.recent_logs,
+                    "Source",
+                    "error",
+                    &msg,
+                    config.log_limit,
+                )
+                .await;
+            }
+            for msg in self.apply(&config, &ipv6, &ipv4) {
+                log_to_web(
+                    &state.log_tx,
+                    &state.recent_logs,
+                    "Source",
+                    "info",
+                    &msg,
+                    config.log_limit,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Open the netlink gate where netlink won, announce what changed for polled sources.
+    /// Returns log messages for source switches.
+    fn apply(&self, config: &AppConfig, ipv6: &Selection, ipv4: &Selection) -> Vec<String> {
+        self.update(config, ipv6, ipv4, true)
+    }
+
+    fn update(
+        &self,
+        config: &AppConfig,
+        ipv6: &Selection,
+        ipv4: &Selection,
+        announce: bool,
+    ) -> Vec<String> {
+        let netlink_listed = config
+            .effective_sources()
+            .iter()
+            .any(|s| matches!(s, SourceConfig::Netlink));
+
+        let mut wanted = Vec::new();
+        let mut messages = Vec::new();
+        let mut announced = self.announced.lock().unwrap();
+        for (selection, is_ipv4) in [(ipv6, false), (ipv4, true)] {
+            // Netlink reports its own changes; with nothing found anywhere it is the one to wait for
+            let open = netlink_listed && (selection.live || selection.source.is_none());
+            self.gate.set(is_ipv4, open);
+            if !selection.live {
+                wanted.extend(selection.addresses.iter().cloned());
+            }
+
+            let previous = if is_ipv4 {
+                &mut announced.ipv4_source
+            } else {
+                &mut announced.ipv6_source
+            };
+            if *previous != selection.name {
+                if let Some(name) = &selection.name {
+                    messages.push(format!(
+                        "{} source: {}",
+                        if is_ipv4 { "IPv4" } else { "IPv6" },
+                        name
+                    ));
+                }
+                previous.clone_from(&selection.name);
+            }
+        }
+
+        let same =
+            |a: &AddressEvent, b: &AddressEvent| a.address == b.address && a.if_name == b.if_name;
+        if !announce {
+            announced.addresses = wanted;
+            return messages;
+        }
+        for old in &announced.addresses {
+            if !wanted.iter().any(|new| same(old, new)) {
+                let _ = self.tx.send(AddressEvent {
+                    kind: AddressEventKind::Removed,
+                    ..old.clone()
+                });
+            }
+        }
+        for new in &wanted {
+            if !announced.addresses.iter().any(|old| same(old, new)) {
+                let _ = self.tx.send(new.clone());
+            }
+        }
+        announced.addresses = wanted;
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_json_lookup() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"ip": "203.0.113.7", "data": {"addresses": [{"ip": "2001:db8::1"}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(json_lookup(&json, "ip").unwrap(), "203.0.113.7");
+        assert_eq!(
+            json_lookup(&json, "$.data.addresses.0.ip").unwrap(),
+            "2001:db8::1"
+        );
+        assert!(json_lookup(&json, "data.addresses.1.ip").is_none());
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let events = parse_addresses("2001:db8::1\n203.0.113.7\nnot-an-ip\n", "wan");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].address, IpAddr::from_str("2001:db8::1").unwrap());
+        assert_eq!(events[0].prefix_len, 128);
+        assert_eq!(events[1].prefix_len, 32);
+        assert_eq!(events[1].interface(), "wan");
+    }
+
+    #[tokio::test]
+    async fn test_command_source_fallback() {
+        let config = AppConfig {
+            sources: vec![
+                SourceConfig::Command {
+                    command: "exit 1".to_string(),
+                    interface: "first".to_string(),
+                },
+                SourceConfig::Command {
+                    command: "echo 203.0.113.7".to_string(),
+                    interface: "second".to_string(),
+                },
+                SourceConfig::Command {
+                    command: "echo 2001:db8::1 198.51.100.1".to_string(),
+                    interface: "third".to_string(),
+                },
+            ],
+            ..AppConfig::default()
+        };
+        let (ipv6, ipv4, errors) = select_addresses(
+            &config,
+            &InterfaceFilter::default(),
+            &HttpClients::default(),
+        )
+        .await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(ipv4.source, Some(1));
+        assert_eq!(ipv4.addresses.len(), 1);
+        assert_eq!(ipv6.source, Some(2));
+        assert_eq!(ipv6.addresses[0].interface(), "third");
+
+        let (tx, mut rx) = broadcast::channel(16);
+        let gate = Arc::new(SourceGate::default());
+        let manager = SourceManager::new(tx, gate.clone());
+        assert_eq!(manager.apply(&config, &ipv6, &ipv4).len(), 2);
+        // Netlink is not configured, so its events are ignored
+        assert!(!gate.allows(false) && !gate.allows(true));
+        assert_eq!(rx.try_recv().unwrap().kind, AddressEventKind::Added);
+        assert_eq!(rx.try_recv().unwrap().kind, AddressEventKind::Added);
+
+        // Same result again: nothing to announce
+        assert!(manager.apply(&config, &ipv6, &ipv4).is_empty());
+        assert!(rx.try_recv().is_err());
+
+        // IPv6 gone: withdraw it
+        let empty = Selection::default();
+        manager.apply(&config, &empty, &ipv4);
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.kind, AddressEventKind::Removed);
+        assert!(!event.is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn test_first_poll_repeats_nothing_from_startup() {
+        let config = AppConfig {
+            sources: vec![SourceConfig::Command {
+                command: "echo 2001:db8::1 203.0.113.7".to_string(),
+                interface: "wan".to_string(),
+            }],
+            ..AppConfig::default()
+        };
+        let clients = HttpClients::default();
+        let filter = InterfaceFilter::default();
+
+        // Startup processes the selection itself and hands it over
+        let (ipv6, ipv4, _) = select_addresses(&config, &filter, &clients).await;
+        let (tx, mut rx) = broadcast::channel(16);
+        let manager = SourceManager::new(tx, Arc::new(SourceGate::default()));
+        assert_eq!(manager.prime(&config, &ipv6, &ipv4).len(), 2);
+        assert!(rx.try_recv().is_err());
+
+        let (ipv6, ipv4, _) = select_addresses(&config, &filter, &clients).await;
+        assert!(manager.apply(&config, &ipv6, &ipv4).is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+}
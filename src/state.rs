@@ -0,0 +1,211 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+/// Last known prefixes of an interface
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InterfaceState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_prefix: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4_address: Option<IpAddr>,
+    pub updated_at: String,
+}
+
+/// What a task last published successfully
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TaskState {
+    /// `None` after a withdraw action that removed the record
+    pub address: Option<IpAddr>,
+    /// IPv4 address published alongside `address` by a task of both families
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<Ipv4Addr>,
+    pub pushed_at: String,
+}
+
+impl TaskState {
+    /// Whether the record already holds `address`, and `ipv4` for a task of both families
+    pub fn holds(&self, address: IpAddr, ipv4: Option<Ipv4Addr>) -> bool {
+        self.address == Some(address) && self.ipv4 == ipv4
+    }
+}
+
+/// Runtime state kept across restarts
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PersistedState {
+    #[serde(default)]
+    pub interfaces: HashMap<String, InterfaceState>,
+    /// Keyed by task id
+    #[serde(default)]
+    pub tasks: HashMap<String, TaskState>,
+}
+
+/// `state.json` next to the config file
+#[derive(Clone)]
+pub struct StateManager {
+    pub state: Arc<RwLock<PersistedState>>,
+    file_path: String,
+    /// Held while a save writes and renames the temporary file
+    save_lock: Arc<Mutex<()>>,
+}
+
+impl StateManager {
+    pub async fn new(config_path: &str) -> Self {
+        let file_path = Path::new(config_path)
+            .with_file_name("state.json")
+            .to_string_lossy()
+            .into_owned();
+        let state = if Path::new(&file_path).exists() {
+            let content = tokio::fs::read_to_string(&file_path)
+                .await
+                .unwrap_or_default();
+            serde_json::from_str(&content).unwrap_or_default()
+        } else {
+            PersistedState::default()
+        };
+
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            file_path,
+            save_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        // One save at a time, so the newest state is also the last one renamed into place
+        let _guard = self.save_lock.lock().await;
+        let content = serde_json::to_string_pretty(&*self.state.read().await)?;
+        // Write a sibling file first so a crash never leaves a truncated state behind
+        let tmp_path = format!("{}.tmp", self.file_path);
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &self.file_path).await?;
+        Ok(())
+    }
+
+    /// Prefix (IPv6) or address (IPv4) last seen on `interface`
+    pub async fn get_prefix(&self, interface: &str, ipv4: bool) -> Option<IpAddr> {
+        let state = self.state.read().await;
+        let entry = state.interfaces.get(interface)?;
+        if ipv4 {
+            entry.ipv4_address
+        } else {
+            entry.ipv6_prefix
+        }
+    }
+
+    /// Remember the current prefix of `interface`, `None` once it was withdrawn
+    pub async fn set_prefix(
+        &self,
+        interface: &str,
+        ipv4: bool,
+        prefix: Option<IpAddr>,
+    ) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            let entry = state.interfaces.entry(interface.to_string()).or_default();
+            if ipv4 {
+                entry.ipv4_address = prefix;
+            } else {
+                entry.ipv6_prefix = prefix;
+            }
+            entry.updated_at = Utc::now().to_rfc3339();
+        }
+        self.save().await
+    }
+
+    pub async fn last_pushed(&self, task_id: &str) -> Option<TaskState> {
+        self.state.read().await.tasks.get(task_id).cloned()
+    }
+
+    /// Remember what `task_id` published
+    pub async fn set_pushed(
+        &self,
+        task_id: &str,
+        address: Option<IpAddr>,
+        ipv4: Option<Ipv4Addr>,
+    ) -> Result<()> {
+        self.state.write().await.tasks.insert(
+            task_id.to_string(),
+            TaskState {
+                address,
+                ipv4,
+                pushed_at: Utc::now().to_rfc3339(),
+            },
+        );
+        self.save().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("prefixddns-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json").to_string_lossy().into_owned();
+        let prefix = IpAddr::from_str("2001:db8:1::").unwrap();
+        let address = IpAddr::from_str("2001:db8:1::1").unwrap();
+
+        let manager = StateManager::new(&config_path).await;
+        manager
+            .set_prefix("wan0", false, Some(prefix))
+            .await
+            .unwrap();
+        manager
+            .set_pushed("task-1", Some(address), None)
+            .await
+            .unwrap();
+        assert!(dir.join("state.json").exists());
+
+        let restarted = StateManager::new(&config_path).await;
+        assert_eq!(restarted.get_prefix("wan0", false).await, Some(prefix));
+        assert_eq!(restarted.get_prefix("wan0", true).await, None);
+        assert_eq!(
+            restarted.last_pushed("task-1").await.unwrap().address,
+            Some(address)
+        );
+        assert!(restarted.last_pushed("task-2").await.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves_keep_every_task() {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixddns-state-concurrent-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json").to_string_lossy().into_owned();
+        let address = IpAddr::from_str("2001:db8:1::1").unwrap();
+
+        let manager = StateManager::new(&config_path).await;
+        let saves: Vec<_> = (0..16)
+            .map(|i| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    manager
+                        .set_pushed(&format!("task-{}", i), Some(address), None)
+                        .await
+                })
+            })
+            .collect();
+        for save in saves {
+            save.await.unwrap().unwrap();
+        }
+
+        let restarted = StateManager::new(&config_path).await;
+        assert_eq!(restarted.state.read().await.tasks.len(), 16);
+        assert!(!dir.join("state.json.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::Result;
+use base64::Engine;
+use minijinja::{Environment, Error, ErrorKind, Value};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+/// Template engine for webhook URLs and bodies (Jinja syntax, e.g. `{{ combined_ip | expanded }}`)
+fn environment() -> &'static Environment<'static> {
+    static ENV: OnceLock<Environment<'static>> = OnceLock::new();
+    ENV.get_or_init(|| {
+        let mut env = Environment::new();
+        env.add_filter("json", json);
+        env.add_filter("base64", base64);
+        env.add_filter("expanded", expanded);
+        env.add_filter("compressed", compressed);
+        env.add_filter("ptr", ptr);
+        env.add_filter("network", network);
+        env.add_filter("prefix_len", prefix_len);
+        env
+    })
+}
+
+/// Render `source` with the fields of `context` as variables
+pub fn render<S: Serialize>(source: &str, context: &S) -> Result<String> {
+    environment()
+        .render_str(source, context)
+        .map_err(|e| anyhow::anyhow!("Template error: {}", describe(&e)))
+}
+
+/// Check the syntax of `source`, and its filter and function names by rendering it with
+/// `sample`. Other rendering errors may depend on the actual values and are not reported.
+pub fn validate<S: Serialize>(source: &str, sample: &S) -> Result<()> {
+    let env = environment();
+    let result = env
+        .template_from_str(source)
+        .and_then(|template| template.render(sample));
+    match result {
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::SyntaxError
+                    | ErrorKind::UnknownFilter
+                    | ErrorKind::UnknownTest
+                    | ErrorKind::UnknownFunction
+            ) =>
+        {
+            Err(anyhow::anyhow!("Template error: {}", describe(&e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Evaluate a condition such as `not record_id` or `ipv4 and source == "Netlink"`
+pub fn is_true<S: Serialize>(expression: &str, context: &S) -> Result<bool> {
+    environment()
+        .compile_expression(expression)
+        .and_then(|expression| expression.eval(context))
+        .map(|value| value.is_true())
+        .map_err(|e| anyhow::anyhow!("Condition error: {}", describe(&e)))
+}
+
+/// Check the syntax of a condition
+pub fn validate_expression(expression: &str) -> Result<()> {
+    environment()
+        .compile_expression(expression)
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("Condition error: {}", describe(&e)))
+}
+
+/// minijinja's `Display` omits the line, which matters for multi-line bodies
+fn describe(error: &Error) -> String {
+    match error.line() {
+        Some(line) => format!("{} (line {})", error, line),
+        None => error.to_string(),
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidOperation, message)
+}
+
+/// Split `2001:db8::/56` (or a bare address) into the address and length
+fn parse_cidr(value: &str) -> Result<(IpAddr, Option<u8>), Error> {
+    let (address, len) = match value.split_once('/') {
+        Some((address, len)) => (address, Some(len)),
+        None => (value, None),
+    };
+    let address: IpAddr = address
+        .trim()
+        .parse()
+        .map_err(|_| invalid(format!("{:?} is not an IP address", value)))?;
+    let len = match len {
+        Some(len) => Some(
+            len.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= if address.is_ipv4() { 32 } else { 128 })
+                .ok_or_else(|| invalid(format!("{:?} has an invalid prefix length", value)))?,
+        ),
+        None => None,
+    };
+    Ok((address, len))
+}
+
+fn with_len(address: String, len: Option<u8>) -> String {
+    match len {
+        Some(len) => format!("{}/{}", address, len),
+        None => address,
+    }
+}
+
+/// JSON literal, quotes included: `"{{ name }}"` breaks on quotes, `{{ name | json }}` does not
+fn json(value: Value) -> Result<String, Error> {
+    serde_json::to_string(&value).map_err(|e| invalid(e.to_string()))
+}
+
+fn base64(value: String) -> String {
+    base64::engine::general_purpose::STANDARD.encode(value)
+}
+
+/// All eight IPv6 groups with leading zeros, e.g. `2001:0db8:0000:...`
+fn expanded(value: String) -> Result<String, Error> {
+    let (address, len) = parse_cidr(&value)?;
+    let text = match address {
+        IpAddr::V6(v6) => v6
+            .segments()
+            .iter()
+            .map(|segment| format!("{:04x}", segment))
+            .collect::<Vec<_>>()
+            .join(":"),
+        IpAddr::V4(v4) => v4.to_string(),
+    };
+    Ok(with_len(text, len))
+}
+
+/// Canonical (RFC 5952) form
+fn compressed(value: String) -> Result<String, Error> {
+    let (address, len) = parse_cidr(&value)?;
+    Ok(with_len(address.to_string(), len))
+}
+
+/// Reverse DNS name. For a prefix, the zone covering it (whole nibbles or octets only).
+fn ptr(value: String) -> Result<String, Error> {
+    let (address, len) = parse_cidr(&value)?;
+    Ok(match address {
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .flat_map(|b| [b >> 4, b & 0xf])
+                .take(len.map_or(32, |len| len as usize / 4))
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            let mut labels: Vec<String> = nibbles.into_iter().rev().collect();
+            labels.push("ip6.arpa".to_string());
+            labels.join(".")
+        }
+        IpAddr::V4(v4) => {
+            let mut labels: Vec<String> = v4
+                .octets()
+                .iter()
+                .take(len.map_or(4, |len| len as usize / 8))
+                .map(|octet| octet.to_string())
+                .rev()
+                .collect();
+            labels.push("in-addr.arpa".to_string());
+            labels.join(".")
+        }
+    })
+}
+
+/// Address part of a prefix: `2001:db8::/56` becomes `2001:db8::`
+fn network(value: String) -> Result<String, Error> {
+    Ok(parse_cidr(&value)?.0.to_string())
+}
+
+/// Length part of a prefix: `2001:db8::/56` becomes `56`
+fn prefix_len(value: String) -> Result<u8, Error> {
+    let (address, len) = parse_cidr(&value)?;
+    Ok(len.unwrap_or(if address.is_ipv4() { 32 } else { 128 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vars() -> serde_json::Value {
+        json!({
+            "combined_ip": "2001:db8:ab00::1",
+            "prefix": "2001:db8:ab00::/56",
+            "name": "host \"a\"&b",
+        })
+    }
+
+    #[test]
+    fn test_filters() {
+        let cases = [
+            ("{{ combined_ip }}", "2001:db8:ab00::1"),
+            (
+                "{{ combined_ip | expanded }}",
+                "2001:0db8:ab00:0000:0000:0000:0000:0001",
+            ),
+            (
+                "{{ '2001:0db8:0:0::1' | compressed | upper }}",
+                "2001:DB8::1",
+            ),
+            ("{{ prefix | network }}", "2001:db8:ab00::"),
+            ("{{ prefix | prefix_len }}", "56"),
+            ("{{ prefix | ptr }}", "0.0.0.0.b.a.8.b.d.0.1.0.0.2.ip6.arpa"),
+            ("{{ '192.0.2.10' | ptr }}", "10.2.0.192.in-addr.arpa"),
+            ("{{ name | json }}", r#""host \"a\"&b""#),
+            ("{{ name | urlencode }}", "host%20%22a%22%26b"),
+            ("{{ 'user:pass' | base64 }}", "dXNlcjpwYXNz"),
+            ("{{ ipv4 | default('none') }}", "none"),
+            ("{% if ipv4 %}A{% else %}AAAA{% endif %}", "AAAA"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(render(source, &vars()).unwrap(), expected, "{}", source);
+        }
+        assert!(render("{{ name | expanded }}", &vars()).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("{{ combined_ip }}", &vars()).is_ok());
+        // Fails only for some values, which the sample cannot tell
+        assert!(validate("{{ name | expanded }}", &vars()).is_ok());
+        assert!(validate("{% if ipv4 %}{{ ipv4 }}", &vars()).is_err());
+        assert!(validate("{{ combined_ip | }}", &vars()).is_err());
+        assert!(validate("{{ combined_ip | nosuch }}", &vars()).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::netlink::{AddressEvent, AddressEventKind};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// Prefix used for change detection: the network for IPv6, the address itself for IPv4
+pub fn address_prefix(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => IpAddr::V6(crate::web::network_prefix(v6, prefix_len)),
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// Current prefix of an interface and the addresses still holding it
+struct InterfacePrefix {
+    prefix: IpAddr,
+    addresses: HashSet<IpAddr>,
+    /// Kernel interface index the prefix was seen on, 0 if it came from another source
+    if_index: u32,
+}
+
+/// Last seen prefix for every interface, tracked separately for IPv4 and IPv6
+#[derive(Default)]
+pub struct PrefixTracker {
+    interfaces: HashMap<(String, bool), InterfacePrefix>,
+}
+
+fn key(interface: &str, address: IpAddr) -> (String, bool) {
+    (interface.to_string(), address.is_ipv4())
+}
+
+impl PrefixTracker {
+    /// Record `address` under `prefix` for `interface`. Returns true if the prefix differs from the previous one.
+    pub fn observe(
+        &mut self,
+        interface: &str,
+        prefix: IpAddr,
+        address: IpAddr,
+        if_index: u32,
+    ) -> bool {
+        let key = key(interface, address);
+        match self.interfaces.get_mut(&key) {
+            Some(current) if current.prefix == prefix => {
+                current.addresses.insert(address);
+                false
+            }
+            _ => {
+                self.interfaces.insert(
+                    key,
+                    InterfacePrefix {
+                        prefix,
+                        addresses: HashSet::from([address]),
+                        if_index,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Forget `address` on `interface`. Returns the prefix if this was its last usable address.
+    pub fn withdraw(&mut self, interface: &str, address: IpAddr) -> Option<IpAddr> {
+        let key = key(interface, address);
+        let current = self.interfaces.get_mut(&key)?;
+        if !current.addresses.remove(&address) || !current.addresses.is_empty() {
+            return None;
+        }
+        self.interfaces.remove(&key).map(|entry| entry.prefix)
+    }
+
+    /// Prefix of the given family currently tracked on `interface`
+    pub fn current_prefix(&self, interface: &str, ipv4: bool) -> Option<IpAddr> {
+        self.interfaces
+            .get(&(interface.to_string(), ipv4))
+            .map(|entry| entry.prefix)
+    }
+
+    /// One of the addresses currently holding the prefix of the given family on `interface`
+    pub fn current_address(&self, interface: &str, ipv4: bool) -> Option<IpAddr> {
+        self.interfaces
+            .get(&(interface.to_string(), ipv4))
+            .and_then(|entry| entry.addresses.iter().next().copied())
+    }
+
+    /// Compare the prefixes learned from the kernel with the addresses it currently reports.
+    /// Extra or vanished addresses of a known prefix are applied directly; the events that should
+    /// have arrived for a new or vanished prefix are returned for normal processing.
+    /// Only families accepted by `include` are compared.
+    pub fn reconcile(
+        &mut self,
+        kernel: &[AddressEvent],
+        prefix_len: u8,
+        include: impl Fn(bool) -> bool,
+    ) -> Vec<AddressEvent> {
+        let mut missed = Vec::new();
+
+        for ((interface, ipv4), entry) in &mut self.interfaces {
+            if entry.if_index == 0 || !include(*ipv4) {
+                continue;
+            }
+            let current: Vec<&AddressEvent> = kernel
+                .iter()
+                .filter(|e| e.is_ipv4() == *ipv4 && e.interface() == *interface)
+                .collect();
+            let same_prefix: Vec<IpAddr> = current
+                .iter()
+                .map(|e| e.address)
+                .filter(|address| address_prefix(*address, prefix_len) == entry.prefix)
+                .collect();
+
+            if same_prefix.is_empty() {
+                match current.first() {
+                    // A different prefix took over
+                    Some(event) => missed.push((*event).clone()),
+                    // The prefix is gone entirely
+                    None => missed.extend(entry.addresses.iter().map(|address| AddressEvent {
+                        kind: AddressEventKind::Removed,
+                        address: *address,
+                        prefix_len: if address.is_ipv4() { 32 } else { 128 },
+                        if_index: entry.if_index,
+                        if_name: Some(interface.clone()),
+                        flags: 0,
+                        valid_lft: None,
+                        preferred_lft: None,
+                    })),
+                }
+                continue;
+            }
+
+            entry.addresses = same_prefix.into_iter().collect();
+        }
+
+        // Interfaces that gained a prefix without us hearing about it
+        for event in kernel {
+            if !include(event.is_ipv4()) {
+                continue;
+            }
+            let key = key(&event.interface(), event.address);
+            let reported = missed
+                .iter()
+                .any(|m| m.is_ipv4() == event.is_ipv4() && m.interface() == event.interface());
+            if !self.interfaces.contains_key(&key) && !reported {
+                missed.push(event.clone());
+            }
+        }
+        missed
+    }
+
+    /// Every interface holding a prefix of the given family, with one of its addresses
+    pub fn current_addresses(&self, ipv4: bool) -> impl Iterator<Item = (&str, IpAddr)> {
+        self.interfaces
+            .iter()
+            .filter(move |((_, is_ipv4), _)| *is_ipv4 == ipv4)
+            .filter_map(|((interface, _), entry)| {
+                let address = entry.addresses.iter().next()?;
+                Some((interface.as_str(), *address))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_prefixes_are_tracked_per_interface() {
+        let wan = addr("2001:db8:1::");
+        let vpn = addr("2001:db8:2::");
+        let mut tracker = PrefixTracker::default();
+
+        assert!(tracker.observe("wan0", wan, addr("2001:db8:1::1"), 2));
+        assert!(tracker.observe("wg0", vpn, addr("2001:db8:2::1"), 2));
+        // Alternating interfaces must not look like a change
+        assert!(!tracker.observe("wan0", wan, addr("2001:db8:1::1"), 2));
+        assert!(!tracker.observe("wg0", vpn, addr("2001:db8:2::1"), 2));
+        assert!(tracker.observe("wan0", vpn, addr("2001:db8:2::5"), 2));
+    }
+
+    #[test]
+    fn test_prefix_withdrawn_with_last_address() {
+        let wan = addr("2001:db8:1::");
+        let mut tracker = PrefixTracker::default();
+        tracker.observe("wan0", wan, addr("2001:db8:1::1"), 2);
+        tracker.observe("wan0", wan, addr("2001:db8:1::2"), 2);
+
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:1::1")), None);
+        // Unknown or stale addresses are ignored
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:9::1")), None);
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:1::2")), Some(wan));
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:1::2")), None);
+
+        // The same prefix coming back counts as new again
+        assert!(tracker.observe("wan0", wan, addr("2001:db8:1::1"), 2));
+    }
+
+    fn kernel_event(address: &str) -> AddressEvent {
+        AddressEvent {
+            kind: AddressEventKind::Added,
+            address: addr(address),
+            prefix_len: 64,
+            if_index: 2,
+            if_name: Some("wan0".to_string()),
+            flags: 0,
+            valid_lft: None,
+            preferred_lft: None,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_finds_missed_changes() {
+        let wan = addr("2001:db8:1::");
+        let mut tracker = PrefixTracker::default();
+        tracker.observe("wan0", wan, addr("2001:db8:1::1"), 2);
+        tracker.observe("wan0", wan, addr("2001:db8:1::2"), 2);
+        // Reported by another source, never reconciled against the kernel
+        tracker.observe("public", wan, addr("2001:db8:1::9"), 0);
+
+        // Same prefix, one address replaced: nothing to report
+        let kernel = vec![kernel_event("2001:db8:1::1"), kernel_event("2001:db8:1::3")];
+        assert!(tracker.reconcile(&kernel, 64, |_| true).is_empty());
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:1::2")), None);
+        assert_eq!(tracker.withdraw("wan0", addr("2001:db8:1::1")), None);
+
+        // A new prefix appeared while we were not listening
+        let kernel = vec![kernel_event("2001:db8:2::1")];
+        let missed = tracker.reconcile(&kernel, 64, |_| true);
+        assert_eq!(missed, vec![kernel_event("2001:db8:2::1")]);
+
+        // Excluded families are left alone
+        assert!(tracker.reconcile(&[], 64, |ipv4| ipv4).is_empty());
+
+        // The prefix vanished entirely
+        let missed = tracker.reconcile(&[], 64, |_| true);
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].kind, AddressEventKind::Removed);
+        assert_eq!(missed[0].address, addr("2001:db8:1::3"));
+
+        // A fresh IPv4 address on an untracked family
+        let mut v4 = kernel_event("203.0.113.7");
+        v4.prefix_len = 32;
+        let missed = tracker.reconcile(&[kernel_event("2001:db8:1::3"), v4.clone()], 64, |_| true);
+        assert_eq!(missed, vec![v4]);
+    }
+
+    #[test]
+    fn test_families_are_tracked_separately() {
+        let v6 = addr("2001:db8:1::");
+        let v4 = addr("203.0.113.7");
+        let mut tracker = PrefixTracker::default();
+
+        assert!(tracker.observe("wan0", v6, addr("2001:db8:1::1"), 2));
+        assert!(tracker.observe("wan0", v4, v4, 2));
+        assert!(!tracker.observe("wan0", v6, addr("2001:db8:1::1"), 2));
+        assert_eq!(tracker.current_address("wan0", true), Some(v4));
+        assert_eq!(tracker.withdraw("wan0", v4), Some(v4));
+        assert_eq!(tracker.current_address("wan0", true), None);
+        assert_eq!(
+            tracker.current_address("wan0", false),
+            Some(addr("2001:db8:1::1"))
+        );
+    }
+}
@@ -1,8 +1,15 @@
-use crate::config::{AppConfig, ConfigManager, Task};
+use crate::config::{AppConfig, ConfigManager, IpFamily, SuffixMode, Task, WebhookStep};
+use crate::dhcp::{parse_env_lease, pd_interface, DhcpPdSource};
+use crate::http::{request_timeout, HttpClients};
 use crate::logging::{log_to_web, LogEntry};
+use crate::neighbor::{format_mac, MacAddr, NeighborTable, SharedNeighborTable};
+use crate::provider;
+use crate::retry::{PendingRetry, RetryTracker};
+use crate::state::{StateManager, TaskState};
+use crate::template;
 use axum::{
     extract::{Json, Request, State},
-    http::{header, StatusCode, Uri},
+    http::{header, HeaderName, HeaderValue, StatusCode, Uri},
     middleware::{self, Next},
     response::{
         sse::{Event, Sse},
@@ -11,14 +18,16 @@ use axum::{
     routing::{get, post},
     Router,
 };
-use chrono::Local;
+use chrono::{Local, Utc};
 use colored::Colorize;
 use futures::stream::Stream;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
-use std::net::Ipv6Addr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use tokio::sync::broadcast;
 
 #[derive(RustEmbed)]
@@ -33,6 +42,11 @@ pub struct AppState {
     pub config_manager: ConfigManager,
     pub log_tx: broadcast::Sender<LogEntry>,
     pub recent_logs: Arc<RwLock<VecDeque<LogEntry>>>,
+    pub neighbors: SharedNeighborTable,
+    pub dhcp_pd: Arc<DhcpPdSource>,
+    pub state_manager: StateManager,
+    pub retries: RetryTracker,
+    pub http: HttpClients,
 }
 
 pub async fn start_server(state: AppState, port: u16) {
@@ -41,6 +55,8 @@ pub async fn start_server(state: AppState, port: u16) {
         .route("/api/config", get(get_config).post(update_config))
         .route("/api/test-webhook", post(test_webhook))
         .route("/api/trigger/:task_name", post(trigger_task_handler))
+        .route("/api/dhcp-pd-hook", post(dhcp_pd_hook_handler))
+        .route("/api/retries", get(get_retries))
         .fallback(static_handler)
         .layer(middleware::from_fn(access_log_middleware))
         .with_state(state);
@@ -164,13 +180,27 @@ async fn get_config(State(state): State<AppState>) -> Json<AppConfig> {
     Json(config.clone())
 }
 
+/// Retries currently waiting for their next attempt
+async fn get_retries(State(state): State<AppState>) -> Json<Vec<PendingRetry>> {
+    Json(state.retries.pending())
+}
+
 async fn update_config(
     State(state): State<AppState>,
     Json(new_config): Json<AppConfig>,
 ) -> impl IntoResponse {
+    if let Err(e) = new_config.validate() {
+        return (StatusCode::BAD_REQUEST, format!("Invalid config: {}", e));
+    }
     match state.config_manager.update(new_config).await {
-        Ok(_) => "Config updated",
-        Err(_) => "Failed to update config",
+        Ok(_) => {
+            state.http.clear();
+            (StatusCode::OK, "Config updated".to_string())
+        }
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to update config".to_string(),
+        ),
     }
 }
 
@@ -181,47 +211,80 @@ struct TestWebhookRequest {
 }
 
 async fn test_webhook(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     Json(req): Json<TestWebhookRequest>,
 ) -> impl IntoResponse {
-    let ip = match Ipv6Addr::from_str(&req.fake_ip) {
+    let ip = match IpAddr::from_str(&req.fake_ip) {
         Ok(ip) => ip,
-        Err(_) => return "Invalid IPv6 address".to_string(),
+        Err(_) => return "Invalid IP address".to_string(),
+    };
+
+    let default_prefix_len = state.config_manager.get_prefix_len().await;
+    let previous = state
+        .state_manager
+        .last_pushed(&req.task.id)
+        .await
+        .and_then(|last| last.address);
+    let vars = match TemplateVars::from_input(
+        &req.task,
+        ip,
+        default_prefix_len,
+        &state.neighbors.read().unwrap(),
+    ) {
+        Ok(vars) => vars.with_run(&req.task, "Test", None, previous, default_prefix_len),
+        Err(e) => return format!("Error combining IP: {}", e),
     };
 
-    // Simulate the logic (duplicate from main logic, should be refactored to shared function)
-    let _ = u128::from_str_radix(&req.task.suffix.replace(":", ""), 16);
-    let _suffix_u128 = 0; // Placeholder
+    // Try sending the webhook (fire and forget or wait?)
+    // For test, we wait.
+    match send_webhook(&state.http, &req.task, &vars).await {
+        Ok(outcome) => format!("Webhook sent! {}", outcome),
+        Err(e) => format!("Webhook failed: {}", e),
+    }
+}
 
-    // Actually, let's use a helper function to combine IP.
-    let combined = combine_ip(ip, &req.task.suffix);
+/// Network mask with the top `prefix_len` bits set
+pub fn prefix_mask(prefix_len: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        len if len >= 128 => u128::MAX,
+        len => !(u128::MAX >> len),
+    }
+}
 
-    match combined {
-        Ok(combined_ip) => {
-            // Try sending the webhook (fire and forget or wait?)
-            // For test, we wait.
-            match send_webhook(&req.task, ip, combined_ip, Some(ip)).await {
-                Ok(status) => format!("Webhook sent! Status: {}", status),
-                Err(e) => format!("Webhook failed: {}", e),
-            }
-        }
-        Err(e) => format!("Error combining IP: {}", e),
+/// IPv4 network mask with the top `prefix_len` bits set
+pub fn ipv4_mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len if len >= 32 => u32::MAX,
+        len => !(u32::MAX >> len),
     }
 }
 
-pub fn combine_ip(original_ip: Ipv6Addr, suffix_str: &str) -> anyhow::Result<Ipv6Addr> {
+/// Network address of `ip` under a `/prefix_len` mask
+pub fn network_prefix(ip: Ipv6Addr, prefix_len: u8) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(ip) & prefix_mask(prefix_len))
+}
+
+pub fn combine_ip(
+    original_ip: Ipv6Addr,
+    suffix_str: &str,
+    prefix_len: u8,
+) -> anyhow::Result<Ipv6Addr> {
     // suffix_str e.g. "::1" or "0:0:0:0:0:0:0:1"
-    // If suffix starts with ::, it's relative?
-    // User said: "addr & /64_mask | suffix"
+    // Result is "addr & prefix_mask | suffix & !prefix_mask"
+    if prefix_len == 0 || prefix_len > 128 {
+        anyhow::bail!("Invalid prefix length /{}", prefix_len);
+    }
 
     let original_u128 = u128::from(original_ip);
-    let mask: u128 = !((1u128 << 64) - 1); // Top 64 bits are 1s
+    let mask = prefix_mask(prefix_len);
     let prefix = original_u128 & mask;
 
     // Parse suffix.
     // If user inputs "::1", Ipv6Addr::from_str("::1") gives 0...1.
     // If user inputs "1234::1", it gives 1234...1.
-    // We strictly want the lower 64 bits from the suffix.
+    // We strictly want the bits below the prefix length from the suffix.
     let suffix_addr = Ipv6Addr::from_str(suffix_str)?;
     let suffix_u128 = u128::from(suffix_addr);
 
@@ -230,59 +293,461 @@ pub fn combine_ip(original_ip: Ipv6Addr, suffix_str: &str) -> anyhow::Result<Ipv
     Ok(Ipv6Addr::from(combined_u128))
 }
 
+/// Parse a MAC address written with `:`, `-` or `.` separators (or none)
+pub fn parse_mac(mac: &str) -> anyhow::Result<MacAddr> {
+    let hex: String = mac
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .collect();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid MAC address '{}'", mac);
+    }
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Modified EUI-64 interface identifier for a MAC address, e.g. `52:54:00:12:34:56` -> `::5054:ff:fe12:3456`
+pub fn eui64_interface_id(mac: &str) -> anyhow::Result<Ipv6Addr> {
+    let bytes = parse_mac(mac)?;
+
+    // Insert ff:fe in the middle and flip the universal/local bit
+    let eui64 = [
+        bytes[0] ^ 0x02,
+        bytes[1],
+        bytes[2],
+        0xff,
+        0xfe,
+        bytes[3],
+        bytes[4],
+        bytes[5],
+    ];
+    Ok(Ipv6Addr::from(u64::from_be_bytes(eui64) as u128))
+}
+
+/// Replace the subnet bits between the task's delegated prefix length and `prefix_len` with its subnet ID,
+/// e.g. `2001:db8:ab00:1::1` from a /56 with subnet `0x10` -> `2001:db8:ab00:10::1`
+pub fn apply_subnet_id(address: Ipv6Addr, task: &Task, prefix_len: u8) -> anyhow::Result<Ipv6Addr> {
+    let (delegated_len, subnet_id) = match (task.delegated_prefix_len, task.subnet_id.as_deref()) {
+        (None, None) => return Ok(address),
+        (None, Some(_)) => anyhow::bail!("Subnet ID requires a delegated prefix length"),
+        (Some(len), subnet_id) => (len, subnet_id.unwrap_or("0")),
+    };
+    if delegated_len == 0 || delegated_len >= prefix_len || prefix_len > 128 {
+        anyhow::bail!(
+            "Delegated prefix /{} must be shorter than the prefix /{}",
+            delegated_len,
+            prefix_len
+        );
+    }
+
+    let digits = subnet_id.trim();
+    let digits = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .unwrap_or(digits);
+    let subnet = u128::from_str_radix(digits, 16)
+        .map_err(|_| anyhow::anyhow!("Invalid subnet ID '{}'", subnet_id))?;
+    let subnet_bits = prefix_len - delegated_len;
+    if subnet_bits < 128 && subnet >> subnet_bits != 0 {
+        anyhow::bail!(
+            "Subnet ID {} does not fit in the {} bits between /{} and /{}",
+            subnet_id,
+            subnet_bits,
+            delegated_len,
+            prefix_len
+        );
+    }
+
+    let subnet_mask = prefix_mask(prefix_len) & !prefix_mask(delegated_len);
+    let shifted = if prefix_len == 128 {
+        subnet
+    } else {
+        subnet << (128 - prefix_len)
+    };
+    Ok(Ipv6Addr::from(
+        (u128::from(address) & !subnet_mask) | shifted,
+    ))
+}
+
+/// Combine `original_ip` with the task's interface identifier according to its suffix mode
+pub fn combine_task_ip(
+    original_ip: Ipv6Addr,
+    task: &Task,
+    prefix_len: u8,
+    neighbors: &NeighborTable,
+) -> anyhow::Result<Ipv6Addr> {
+    let original_ip = apply_subnet_id(original_ip, task, prefix_len)?;
+    match task.suffix_mode {
+        SuffixMode::Static => combine_ip(original_ip, &task.suffix, prefix_len),
+        SuffixMode::Eui64 => {
+            let interface_id = eui64_interface_id(task.mac.as_deref().unwrap_or_default())?;
+            // A static suffix may still supply the bits between the prefix and the interface ID
+            let upper = if task.suffix.trim().is_empty() {
+                0
+            } else {
+                u128::from(Ipv6Addr::from_str(&task.suffix)?) & !(u64::MAX as u128)
+            };
+            let suffix = Ipv6Addr::from(upper | u128::from(interface_id));
+            combine_ip(original_ip, &suffix.to_string(), prefix_len)
+        }
+        SuffixMode::Neighbor => {
+            let mac = parse_mac(task.mac.as_deref().unwrap_or_default())?;
+            neighbors
+                .find(&mac, original_ip, prefix_len)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No address of {} seen under {}/{} yet",
+                        format_mac(&mac),
+                        network_prefix(original_ip, prefix_len),
+                        prefix_len
+                    )
+                })
+        }
+    }
+}
+
+/// Values available to the webhook templates. Absent ones are undefined, so `default` applies.
+#[derive(Clone, Debug, Serialize)]
+pub struct TemplateVars {
+    pub combined_ip: IpAddr,
+    pub original_ip: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ip: Option<IpAddr>,
+    /// Network in CIDR notation, e.g. `2001:db8:ab00::/56`
+    pub prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<Ipv4Addr>,
+    pub task_name: String,
+    pub task_id: String,
+    /// What started the run, e.g. `Startup`, `Netlink` or `API`
+    pub source: String,
+    /// Interface the address was detected on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Address the task published before this run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_ip: Option<IpAddr>,
+    /// Prefix of `previous_ip`, in the same notation as `prefix`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_prefix: Option<String>,
+    /// UTC time of the run (RFC 3339)
+    pub timestamp: String,
+    pub hostname: String,
+    /// Environment variables of the process starting with `PREFIXDDNS_`, e.g. `{{ env.PREFIXDDNS_API_TOKEN }}`
+    pub env: HashMap<String, String>,
+}
+
+impl TemplateVars {
+    /// Variables for `task` given the current address of each family
+    pub fn for_task(
+        task: &Task,
+        ipv6: Option<Ipv6Addr>,
+        ipv4: Option<Ipv4Addr>,
+        default_prefix_len: u8,
+        neighbors: &NeighborTable,
+    ) -> anyhow::Result<Self> {
+        if task.address_family == IpFamily::Ipv4 {
+            let Some(v4) = ipv4 else {
+                anyhow::bail!("No IPv4 address available");
+            };
+            return Ok(Self::fixed(IpAddr::V4(v4)));
+        }
+
+        let Some(v6) = ipv6 else {
+            anyhow::bail!("No IPv6 address available");
+        };
+        let prefix_len = task.effective_prefix_len(default_prefix_len);
+        let combined = combine_task_ip(v6, task, prefix_len, neighbors)?;
+        Ok(Self {
+            original_ip: IpAddr::V6(v6),
+            prefix: cidr(IpAddr::V6(combined), prefix_len),
+            ipv4,
+            ..Self::fixed(IpAddr::V6(combined))
+        })
+    }
+
+    /// Variables for an address given by hand (test run or API trigger)
+    pub fn from_input(
+        task: &Task,
+        ip: IpAddr,
+        default_prefix_len: u8,
+        neighbors: &NeighborTable,
+    ) -> anyhow::Result<Self> {
+        let mut vars = match ip {
+            IpAddr::V6(v6) => Self::for_task(task, Some(v6), None, default_prefix_len, neighbors)?,
+            // A task of both families publishes an IPv4 address as it is
+            IpAddr::V4(v4) if task.address_family == IpFamily::Both => Self {
+                ipv4: Some(v4),
+                ..Self::fixed(ip)
+            },
+            IpAddr::V4(v4) => Self::for_task(task, None, Some(v4), default_prefix_len, neighbors)?,
+        };
+        vars.input_ip = Some(ip);
+        Ok(vars)
+    }
+
+    /// Every variable set, for checking templates when the config is saved
+    pub fn sample() -> Self {
+        let original = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x100));
+        Self {
+            original_ip: original,
+            input_ip: Some(original),
+            prefix: "2001:db8::/64".to_string(),
+            ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+            task_name: "Sample".to_string(),
+            task_id: "sample".to_string(),
+            source: "Netlink".to_string(),
+            interface: Some("eth0".to_string()),
+            previous_ip: Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1))),
+            previous_prefix: Some("2001:db8:1::/64".to_string()),
+            ..Self::fixed(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)))
+        }
+    }
+
+    /// Variables publishing `ip` unchanged, e.g. an IPv4 address or a fallback
+    pub fn fixed(ip: IpAddr) -> Self {
+        let (prefix_len, ipv4) = match ip {
+            IpAddr::V4(v4) => (32, Some(v4)),
+            IpAddr::V6(_) => (128, None),
+        };
+        Self {
+            combined_ip: ip,
+            original_ip: ip,
+            input_ip: None,
+            prefix: format!("{}/{}", ip, prefix_len),
+            ipv4,
+            task_name: String::new(),
+            task_id: String::new(),
+            source: String::new(),
+            interface: None,
+            previous_ip: None,
+            previous_prefix: None,
+            timestamp: Utc::now().to_rfc3339(),
+            hostname: hostname(),
+            env: template_env().clone(),
+        }
+    }
+
+    /// IPv4 address published next to `combined_ip`, which only a task of both families does
+    pub fn published_ipv4(&self, task: &Task) -> Option<Ipv4Addr> {
+        match task.address_family {
+            IpFamily::Both => self.ipv4,
+            _ => None,
+        }
+    }
+
+    /// Whether `last` already published these addresses, so the run can be skipped
+    pub fn is_published(&self, task: &Task, last: Option<&TaskState>) -> bool {
+        last.is_some_and(|last| last.holds(self.combined_ip, self.published_ipv4(task)))
+    }
+
+    /// Describe the run: the task, what started it, and what it replaces
+    pub fn with_run(
+        mut self,
+        task: &Task,
+        source: &str,
+        interface: Option<&str>,
+        previous_ip: Option<IpAddr>,
+        default_prefix_len: u8,
+    ) -> Self {
+        self.task_name = task.name.clone();
+        self.task_id = task.id.clone();
+        self.source = source.to_string();
+        self.interface = interface.map(str::to_string);
+        self.previous_ip = previous_ip;
+        self.previous_prefix = previous_ip.map(|ip| match ip {
+            IpAddr::V6(_) => cidr(ip, task.effective_prefix_len(default_prefix_len)),
+            IpAddr::V4(_) => cidr(ip, 32),
+        });
+        self
+    }
+}
+
+/// Check a rendered header, e.g. a value that picked up a line break from a variable
+pub fn parse_header(name: &str, value: &str) -> anyhow::Result<(HeaderName, HeaderValue)> {
+    let header_name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| anyhow::anyhow!("Invalid header name {:?}", name))?;
+    let header_value = HeaderValue::from_str(value.trim())
+        .map_err(|_| anyhow::anyhow!("Invalid value for header {}: {:?}", header_name, value))?;
+    Ok((header_name, header_value))
+}
+
+/// Network of `ip` in CIDR notation
+fn cidr(ip: IpAddr, prefix_len: u8) -> String {
+    match ip {
+        IpAddr::V6(v6) => format!("{}/{}", network_prefix(v6, prefix_len), prefix_len),
+        IpAddr::V4(v4) => format!("{}/{}", v4, prefix_len),
+    }
+}
+
+/// Only environment variables with this prefix are visible to templates
+pub const TEMPLATE_ENV_PREFIX: &str = "PREFIXDDNS_";
+
+/// Environment variables visible to templates, read once
+fn template_env() -> &'static HashMap<String, String> {
+    static ENV: OnceLock<HashMap<String, String>> = OnceLock::new();
+    ENV.get_or_init(|| prefixed_env(std::env::vars()))
+}
+
+fn prefixed_env(vars: impl Iterator<Item = (String, String)>) -> HashMap<String, String> {
+    vars.filter(|(name, _)| name.starts_with(TEMPLATE_ENV_PREFIX))
+        .collect()
+}
+
+/// Name of this machine, empty if it cannot be read
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
+
+/// Run the task's steps, then its webhook, or update the record through its provider.
+/// Returns the outcome for the log, e.g. `HTTP 200`.
 pub async fn send_webhook(
+    clients: &HttpClients,
     task: &Task,
-    original_ip: Ipv6Addr,
-    combined_ip: Ipv6Addr,
-    input_ip: Option<Ipv6Addr>,
-) -> anyhow::Result<u16> {
-    let client = reqwest::Client::builder()
-        .user_agent(concat!("PrefixDDNS/", env!("CARGO_PKG_VERSION")))
-        .build()?;
-
-    let prefix_len = 64; // Hardcoded for now
-    let prefix = format!("{}/{}", original_ip, prefix_len); // Roughly
-
-    // Helper closure for replacement
-    let do_replace = |text: &str| -> String {
-        let mut s = text.to_string();
-        s = s.replace("{{combined_ip}}", &combined_ip.to_string());
-        s = s.replace("{{original_ip}}", &original_ip.to_string());
-        if let Some(input) = input_ip {
-            s = s.replace("{{input_ip}}", &input.to_string());
+    vars: &TemplateVars,
+) -> anyhow::Result<String> {
+    let options = task.http.clone().unwrap_or_default();
+    let client = clients.get(&options)?;
+    let timeout = request_timeout(&options);
+
+    if let Some(provider) = &task.provider {
+        return provider::update(provider, &client, timeout, vars, vars.published_ipv4(task)).await;
+    }
+
+    // Captured values join the template variables
+    let mut context = serde_json::to_value(vars)?;
+    for step in &task.steps {
+        if let Some(when) = &step.when {
+            if !template::is_true(when, &context)? {
+                continue;
+            }
         }
-        s = s.replace("{{prefix}}", &prefix);
-        s
-    };
+        let (_, body) = send_step(&client, timeout, step, &context)
+            .await
+            .map_err(|e| {
+                let msg = format!("Step [{}] failed: {}", step.name, e);
+                e.context(msg)
+            })?;
+        for (name, capture) in &step.captures {
+            if let Some(value) = capture.extract(&body) {
+                context[name] = value;
+            }
+        }
+    }
 
-    let url = do_replace(&task.webhook_url);
-    let body = do_replace(&task.webhook_body.clone().unwrap_or_default());
+    let (status, _) = send_step(&client, timeout, &WebhookStep::from_task(task), &context).await?;
+    Ok(format!("HTTP {}", status))
+}
+
+/// Send one request, returning its status and body if it passes the success criteria
+async fn send_step(
+    client: &reqwest::Client,
+    timeout: Duration,
+    step: &WebhookStep,
+    context: &serde_json::Value,
+) -> anyhow::Result<(u16, String)> {
+    let url = template::render(&step.webhook_url, context)?;
+    let body = template::render(step.webhook_body.as_deref().unwrap_or_default(), context)?;
 
-    let mut req_builder = match task.webhook_method.to_uppercase().as_str() {
+    let mut req_builder = match step.webhook_method.to_uppercase().as_str() {
         "POST" => client.post(&url),
         "PUT" => client.put(&url),
         "PATCH" => client.patch(&url),
+        "DELETE" => client.delete(&url),
         _ => client.get(&url),
     };
 
-    for (k, v) in &task.webhook_headers {
-        req_builder = req_builder.header(k, v);
+    for (name, value) in &step.webhook_headers {
+        let (name, value) = parse_header(
+            &template::render(name, context)?,
+            &template::render(value, context)?,
+        )?;
+        req_builder = req_builder.header(name, value);
     }
 
     if !body.is_empty() {
         req_builder = req_builder.body(body);
     }
+    req_builder = req_builder.timeout(timeout);
 
     let resp = req_builder.send().await?;
     let status = resp.status().as_u16();
+    let body = resp.text().await.unwrap_or_default();
+
+    let criteria = step.success.clone().unwrap_or_default();
+    if let Err(reason) = criteria.check(status, &body) {
+        let body = excerpt(&body);
+        if !criteria.accepts_status(status) {
+            return Err(HttpStatusError { status, body }.into());
+        }
+        return Err(ResponseRejected {
+            status,
+            reason,
+            body,
+        }
+        .into());
+    }
+
+    Ok((status, body))
+}
 
-    if !resp.status().is_success() {
-        anyhow::bail!("HTTP {}", status);
+/// Start of a response body on a single line, for log entries
+pub fn excerpt(body: &str) -> String {
+    const MAX_CHARS: usize = 200;
+    let text = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
     }
+}
+
+/// The webhook answered with a status the task does not accept
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    /// Excerpt of the response body
+    pub body: String,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)?;
+        if !self.body.is_empty() {
+            write!(f, ": {}", self.body)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
 
-    Ok(status)
+/// The status was accepted but the body failed the task's success criteria
+#[derive(Debug)]
+pub struct ResponseRejected {
+    pub status: u16,
+    pub reason: String,
+    /// Excerpt of the response body
+    pub body: String,
 }
 
+impl std::fmt::Display for ResponseRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "HTTP {} but {}, response: {:?}",
+            self.status, self.reason, self.body
+        )
+    }
+}
+
+impl std::error::Error for ResponseRejected {}
+
 #[derive(Deserialize)]
 struct TriggerRequest {
     ip: String,
@@ -314,13 +779,71 @@ impl<T> ApiResponse<T> {
     }
 }
 
+#[derive(Deserialize)]
+struct DhcpPdHookQuery {
+    interface: Option<String>,
+}
+
+/// Receives the environment of a DHCPv6 client hook, e.g. `env | curl --data-binary @- .../api/dhcp-pd-hook`
+async fn dhcp_pd_hook_handler(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<DhcpPdHookQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let config = state.config_manager.config.read().await.clone();
+    if !config.dhcp_pd.hook_enabled {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ApiResponse::<()>::error("DHCPv6-PD hook disabled")),
+        );
+    }
+
+    let lease = parse_env_lease(&body);
+    let interface = pd_interface(
+        query
+            .interface
+            .or(lease.interface)
+            .or(config.dhcp_pd.interface),
+    );
+    let prefixes = lease
+        .prefixes
+        .iter()
+        .map(|p| format!("{}/{}", p.prefix, p.prefix_len))
+        .collect::<Vec<_>>();
+
+    let changes = state
+        .dhcp_pd
+        .update(&format!("hook:{}", interface), &interface, lease.prefixes);
+    let msg = format!(
+        "Hook on {}: delegated prefixes [{}], {} change(s)",
+        interface,
+        prefixes.join(", "),
+        changes
+    );
+    log_to_web(
+        &state.log_tx,
+        &state.recent_logs,
+        "DHCPv6-PD",
+        "info",
+        &msg,
+        config.log_limit,
+    )
+    .await;
+
+    (StatusCode::OK, Json(ApiResponse::<()>::success(&msg, None)))
+}
+
 async fn trigger_task_handler(
     State(state): State<AppState>,
     axum::extract::Path(task_name): axum::extract::Path<String>,
     Json(req): Json<TriggerRequest>,
 ) -> impl IntoResponse {
-    let config = state.config_manager.config.read().await;
-    let task = config.tasks.iter().find(|t| t.name == task_name);
+    // Copy what the run needs, so a slow webhook never holds up a config save
+    let (task, prefix_len, log_limit) = {
+        let config = state.config_manager.config.read().await;
+        let task = config.tasks.iter().find(|t| t.name == task_name).cloned();
+        (task, config.prefix_len, config.log_limit)
+    };
 
     if let Some(task) = task {
         if !task.allow_api_trigger {
@@ -333,32 +856,38 @@ async fn trigger_task_handler(
                 .into_response();
         }
 
-        let ip = match Ipv6Addr::from_str(&req.ip) {
+        let ip = match IpAddr::from_str(&req.ip) {
             Ok(ip) => ip,
             Err(_) => {
                 return (
                     axum::http::StatusCode::BAD_REQUEST,
-                    Json(ApiResponse::<()>::error("Invalid IPv6 address")),
+                    Json(ApiResponse::<()>::error("Invalid IP address")),
                 )
                     .into_response()
             }
         };
 
-        let combined = match combine_ip(ip, &task.suffix) {
-            Ok(c) => c,
-            Err(e) => {
-                return (
-                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ApiResponse::<()>::error(&format!(
-                        "IP combination error: {}",
-                        e
-                    ))),
-                )
-                    .into_response()
-            }
-        };
+        let previous = state
+            .state_manager
+            .last_pushed(&task.id)
+            .await
+            .and_then(|last| last.address);
+        let vars =
+            match TemplateVars::from_input(&task, ip, prefix_len, &state.neighbors.read().unwrap())
+            {
+                Ok(v) => v.with_run(&task, "API", None, previous, prefix_len),
+                Err(e) => {
+                    return (
+                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(ApiResponse::<()>::error(&format!(
+                            "IP combination error: {}",
+                            e
+                        ))),
+                    )
+                        .into_response()
+                }
+            };
 
-        let log_limit = config.log_limit;
         let log_msg = format!("Task [{}]: Running for {}", task.name, ip);
         log_to_web(
             &state.log_tx,
@@ -370,9 +899,28 @@ async fn trigger_task_handler(
         )
         .await;
 
-        match send_webhook(task, ip, combined, Some(ip)).await {
-            Ok(status) => {
-                let success_msg = format!("Task [{}]: Success (HTTP {})", task.name, status);
+        // The caller gets the outcome right away, so this is a single attempt that
+        // replaces whatever retry an earlier run was still waiting on
+        state.retries.begin(&task.id);
+        match send_webhook(&state.http, &task, &vars).await {
+            Ok(outcome) => {
+                if let Err(e) = state
+                    .state_manager
+                    .set_pushed(&task.id, Some(vars.combined_ip), vars.published_ipv4(&task))
+                    .await
+                {
+                    let msg = format!("Task [{}]: Failed to save state: {}", task.name, e);
+                    log_to_web(
+                        &state.log_tx,
+                        &state.recent_logs,
+                        "System",
+                        "error",
+                        &msg,
+                        log_limit,
+                    )
+                    .await;
+                }
+                let success_msg = format!("Task [{}]: Success ({})", task.name, outcome);
                 log_to_web(
                     &state.log_tx,
                     &state.recent_logs,
@@ -418,13 +966,15 @@ async fn trigger_task_handler(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::SuccessCriteria;
+    use crate::neighbor::NeighborEvent;
     use std::str::FromStr;
 
     #[test]
     fn test_combine_ip_basic() {
         let prefix = Ipv6Addr::from_str("2001:db8:1:1::100").unwrap();
         let suffix = "::1";
-        let combined = combine_ip(prefix, suffix).unwrap();
+        let combined = combine_ip(prefix, suffix, 64).unwrap();
         assert_eq!(combined.to_string(), "2001:db8:1:1::1");
     }
 
@@ -432,7 +982,7 @@ mod tests {
     fn test_combine_ip_complex_suffix() {
         let prefix = Ipv6Addr::from_str("2001:db8:aaaa:bbbb::1").unwrap();
         let suffix = "::dead:beef";
-        let combined = combine_ip(prefix, suffix).unwrap();
+        let combined = combine_ip(prefix, suffix, 64).unwrap();
         assert_eq!(combined.to_string(), "2001:db8:aaaa:bbbb::dead:beef");
     }
 
@@ -440,7 +990,7 @@ mod tests {
     fn test_combine_ip_full_suffix() {
         let prefix = Ipv6Addr::from_str("2001:db8::1").unwrap();
         let suffix = "0:0:0:0:1:2:3:4";
-        let combined = combine_ip(prefix, suffix).unwrap();
+        let combined = combine_ip(prefix, suffix, 64).unwrap();
         assert_eq!(combined.to_string(), "2001:db8::1:2:3:4");
     }
 
@@ -448,6 +998,501 @@ mod tests {
     fn test_combine_ip_invalid_suffix() {
         let prefix = Ipv6Addr::from_str("2001:db8::1").unwrap();
         let suffix = "invalid";
-        assert!(combine_ip(prefix, suffix).is_err());
+        assert!(combine_ip(prefix, suffix, 64).is_err());
+    }
+
+    #[test]
+    fn test_combine_ip_prefix_56() {
+        let prefix = Ipv6Addr::from_str("2001:db8:abcd:ef12::100").unwrap();
+        let suffix = "::34:0:0:0:1";
+        let combined = combine_ip(prefix, suffix, 56).unwrap();
+        assert_eq!(combined.to_string(), "2001:db8:abcd:ef34::1");
+    }
+
+    #[test]
+    fn test_combine_ip_invalid_prefix_len() {
+        let prefix = Ipv6Addr::from_str("2001:db8::1").unwrap();
+        assert!(combine_ip(prefix, "::1", 0).is_err());
+        assert!(combine_ip(prefix, "::1", 129).is_err());
+    }
+
+    #[test]
+    fn test_network_prefix() {
+        let ip = Ipv6Addr::from_str("2001:db8:abcd:ef12::100").unwrap();
+        assert_eq!(network_prefix(ip, 56).to_string(), "2001:db8:abcd:ef00::");
+        assert_eq!(network_prefix(ip, 48).to_string(), "2001:db8:abcd::");
+        assert_eq!(network_prefix(ip, 128), ip);
+    }
+
+    fn task(family: IpFamily) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "id": "t1",
+            "name": "Test",
+            "suffix": "::1",
+            "webhook_url": "http://localhost/",
+            "webhook_method": "GET",
+            "webhook_body": null,
+            "webhook_headers": {},
+            "address_family": family,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_eui64_interface_id() {
+        let id = eui64_interface_id("52:54:00:12:34:56").unwrap();
+        assert_eq!(id.to_string(), "::5054:ff:fe12:3456");
+        let id = eui64_interface_id("00-1A-2B-3C-4D-5E").unwrap();
+        assert_eq!(id.to_string(), "::21a:2bff:fe3c:4d5e");
+        assert!(eui64_interface_id("52:54:00:12:34").is_err());
+        assert!(eui64_interface_id("zz:54:00:12:34:56").is_err());
+    }
+
+    #[test]
+    fn test_combine_task_ip_eui64() {
+        let mut task = task(IpFamily::Ipv6);
+        task.suffix_mode = SuffixMode::Eui64;
+        task.mac = Some("52:54:00:12:34:56".to_string());
+        task.suffix = String::new();
+        let prefix = Ipv6Addr::from_str("2001:db8:1:2::abc").unwrap();
+        let combined = combine_task_ip(prefix, &task, 64, &NeighborTable::default()).unwrap();
+        assert_eq!(combined.to_string(), "2001:db8:1:2:5054:ff:fe12:3456");
+
+        task.mac = Some("not-a-mac".to_string());
+        assert!(combine_task_ip(prefix, &task, 64, &NeighborTable::default()).is_err());
+    }
+
+    #[test]
+    fn test_combine_task_ip_subnet_id() {
+        let mut task = task(IpFamily::Ipv6);
+        task.suffix = "::1".to_string();
+        task.delegated_prefix_len = Some(56);
+        task.subnet_id = Some("0x10".to_string());
+        let wan = Ipv6Addr::from_str("2001:db8:ab12:3401::abc").unwrap();
+        let neighbors = NeighborTable::default();
+        let combined = combine_task_ip(wan, &task, 64, &neighbors).unwrap();
+        assert_eq!(combined.to_string(), "2001:db8:ab12:3410::1");
+
+        // Subnet 0x100 needs 9 bits, a /56 only leaves 8
+        task.subnet_id = Some("100".to_string());
+        assert!(combine_task_ip(wan, &task, 64, &neighbors).is_err());
+        task.subnet_id = Some("0x10".to_string());
+        task.delegated_prefix_len = Some(64);
+        assert!(combine_task_ip(wan, &task, 64, &neighbors).is_err());
+        task.delegated_prefix_len = None;
+        assert!(combine_task_ip(wan, &task, 64, &neighbors).is_err());
+    }
+
+    #[test]
+    fn test_combine_task_ip_neighbor() {
+        let mut task = task(IpFamily::Ipv6);
+        task.suffix_mode = SuffixMode::Neighbor;
+        task.mac = Some("52:54:00:12:34:56".to_string());
+        let host = Ipv6Addr::from_str("2001:db8:1:2:1c2d:3e4f:5a6b:7c8d").unwrap();
+        let mut neighbors = NeighborTable::default();
+        neighbors.update(&NeighborEvent {
+            mac: parse_mac("52:54:00:12:34:56").unwrap(),
+            address: host,
+            if_index: 3,
+            if_name: None,
+            removed: false,
+        });
+
+        let prefix = Ipv6Addr::from_str("2001:db8:1:2::abc").unwrap();
+        assert_eq!(
+            combine_task_ip(prefix, &task, 64, &neighbors).unwrap(),
+            host
+        );
+        // Not seen under the new prefix yet
+        let other = Ipv6Addr::from_str("2001:db8:9:2::abc").unwrap();
+        assert!(combine_task_ip(other, &task, 64, &neighbors).is_err());
+    }
+
+    #[test]
+    fn test_template_vars_per_family() {
+        let v6 = Ipv6Addr::from_str("2001:db8:1:2::abc").unwrap();
+        let v4 = Ipv4Addr::new(203, 0, 113, 7);
+
+        let vars = TemplateVars::for_task(
+            &task(IpFamily::Ipv6),
+            Some(v6),
+            Some(v4),
+            64,
+            &NeighborTable::default(),
+        )
+        .unwrap();
+        assert_eq!(vars.combined_ip.to_string(), "2001:db8:1:2::1");
+        assert_eq!(vars.prefix, "2001:db8:1:2::/64");
+        assert_eq!(vars.ipv4, Some(v4));
+
+        let vars = TemplateVars::for_task(
+            &task(IpFamily::Ipv4),
+            Some(v6),
+            Some(v4),
+            64,
+            &NeighborTable::default(),
+        )
+        .unwrap();
+        assert_eq!(vars.combined_ip, IpAddr::V4(v4));
+        assert_eq!(vars.prefix, "203.0.113.7/32");
+
+        assert!(TemplateVars::for_task(
+            &task(IpFamily::Ipv4),
+            Some(v6),
+            None,
+            64,
+            &NeighborTable::default()
+        )
+        .is_err());
+        assert!(TemplateVars::for_task(
+            &task(IpFamily::Both),
+            None,
+            Some(v4),
+            64,
+            &NeighborTable::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_address_per_family() {
+        let v4 = Ipv4Addr::new(203, 0, 113, 7);
+        let input = |family, ip: &str| {
+            TemplateVars::from_input(
+                &task(family),
+                IpAddr::from_str(ip).unwrap(),
+                64,
+                &NeighborTable::default(),
+            )
+        };
+
+        let vars = input(IpFamily::Both, "203.0.113.7").unwrap();
+        assert_eq!(vars.combined_ip, IpAddr::V4(v4));
+        assert_eq!(vars.ipv4, Some(v4));
+        assert_eq!(vars.input_ip, Some(IpAddr::V4(v4)));
+
+        let vars = input(IpFamily::Both, "2001:db8:1:2::abc").unwrap();
+        assert_eq!(vars.combined_ip.to_string(), "2001:db8:1:2::1");
+
+        assert_eq!(
+            input(IpFamily::Ipv4, "203.0.113.7").unwrap().combined_ip,
+            IpAddr::V4(v4)
+        );
+        assert!(input(IpFamily::Ipv6, "203.0.113.7").is_err());
+        assert!(input(IpFamily::Ipv4, "2001:db8:1:2::abc").is_err());
+    }
+
+    #[test]
+    fn test_dual_stack_ipv4_change_is_published() {
+        let task = task(IpFamily::Both);
+        let v6 = Some(Ipv6Addr::from_str("2001:db8:1:2::abc").unwrap());
+        let vars = |v4| {
+            TemplateVars::for_task(&task, v6, Some(v4), 64, &NeighborTable::default()).unwrap()
+        };
+        let old = vars(Ipv4Addr::new(203, 0, 113, 7));
+        let last = TaskState {
+            address: Some(old.combined_ip),
+            ipv4: old.published_ipv4(&task),
+            pushed_at: String::new(),
+        };
+        assert!(old.is_published(&task, Some(&last)));
+
+        // Same IPv6 address, new IPv4 address
+        let new = vars(Ipv4Addr::new(203, 0, 113, 8));
+        assert_eq!(new.combined_ip, old.combined_ip);
+        assert!(!new.is_published(&task, Some(&last)));
+        assert!(!new.is_published(&task, None));
+
+        // Tasks of one family ignore the other
+        let task = self::task(IpFamily::Ipv6);
+        assert_eq!(new.published_ipv4(&task), None);
+    }
+
+    #[test]
+    fn test_run_variables() {
+        let task = task(IpFamily::Ipv6);
+        let previous = IpAddr::from_str("2001:db8:1:1::1").unwrap();
+        let vars = TemplateVars::for_task(
+            &task,
+            Some(Ipv6Addr::from_str("2001:db8:2:1::abc").unwrap()),
+            None,
+            64,
+            &NeighborTable::default(),
+        )
+        .unwrap()
+        .with_run(&task, "Netlink", Some("ppp0"), Some(previous), 64);
+
+        let vars = TemplateVars {
+            env: prefixed_env(
+                [
+                    ("PREFIXDDNS_TEST_TOKEN", "secret"),
+                    ("HOME", "/root"),
+                    ("AWS_SECRET_ACCESS_KEY", "hidden"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+            ),
+            ..vars
+        };
+        assert_eq!(vars.env.len(), 1);
+        let rendered = crate::template::render(
+            "{{ task_name }}/{{ task_id }} {{ source }} {{ interface }}: \
+             {{ previous_ip }} ({{ previous_prefix }}) -> {{ combined_ip }} \
+             {{ env.PREFIXDDNS_TEST_TOKEN }}",
+            &vars,
+        )
+        .unwrap();
+        assert_eq!(
+            rendered,
+            "Test/t1 Netlink ppp0: 2001:db8:1:1::1 (2001:db8:1:1::/64) -> 2001:db8:2:1::1 secret"
+        );
+        assert!(chrono::DateTime::parse_from_rfc3339(&vars.timestamp).is_ok());
+    }
+
+    #[test]
+    fn test_templated_headers() {
+        let mut task = task(IpFamily::Ipv6);
+        task.webhook_headers.insert(
+            "X-Request-Address".to_string(),
+            "{{ combined_ip }}".to_string(),
+        );
+        task.webhook_headers
+            .insert("X-{{ source }}".to_string(), "1".to_string());
+        assert!(task.validate_templates().is_ok());
+
+        let (name, value) = parse_header(
+            &crate::template::render("X-{{ source }}", &TemplateVars::sample()).unwrap(),
+            "1",
+        )
+        .unwrap();
+        assert_eq!(name.as_str(), "x-netlink");
+        assert_eq!(value, "1");
+
+        assert!(parse_header("X-Bad Name", "1").is_err());
+        assert!(parse_header("X-Multi", "a\nb").is_err());
+        task.webhook_headers
+            .insert("X-Host".to_string(), "{{ hostname }}\r\nX: y".to_string());
+        assert!(task.validate_templates().is_err());
+    }
+
+    #[test]
+    fn test_success_criteria() {
+        let duckdns = SuccessCriteria {
+            body_contains: Some("OK".to_string()),
+            ..Default::default()
+        };
+        assert!(duckdns.check(200, "OK").is_ok());
+        assert!(duckdns.check(200, "KO").is_err());
+        assert!(duckdns.check(500, "OK").is_err());
+
+        let dynu = SuccessCriteria {
+            body_regex: Some("^(good|nochg)".to_string()),
+            ..Default::default()
+        };
+        assert!(dynu.check(200, "nochg 2001:db8::1").is_ok());
+        assert!(dynu.check(200, "badauth").is_err());
+
+        let cloudflare = SuccessCriteria {
+            json_path: Some("success".to_string()),
+            json_equals: Some(serde_json::json!(true)),
+            ..Default::default()
+        };
+        assert!(cloudflare.check(200, r#"{"success":true}"#).is_ok());
+        assert_eq!(
+            cloudflare.check(200, r#"{"success":false}"#).unwrap_err(),
+            "success is false, expected true"
+        );
+        assert!(cloudflare.check(200, "<html>").is_err());
+
+        let accepted = SuccessCriteria {
+            status: vec![200, 409],
+            ..Default::default()
+        };
+        assert!(accepted.check(409, "").is_ok());
+        assert!(accepted.check(204, "").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_response_is_logged_with_body() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().route("/update", get(|| async { "KO\n" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut task = task(IpFamily::Ipv6);
+        task.webhook_url = format!("http://127.0.0.1:{}/update", port);
+        let vars = TemplateVars::fixed(IpAddr::from_str("2001:db8::1").unwrap());
+        let clients = HttpClients::default();
+        assert_eq!(
+            send_webhook(&clients, &task, &vars).await.unwrap(),
+            "HTTP 200"
+        );
+
+        task.success = Some(SuccessCriteria {
+            body_contains: Some("OK".to_string()),
+            ..Default::default()
+        });
+        let error = send_webhook(&clients, &task, &vars).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"HTTP 200 but body does not contain "OK", response: "KO""#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_steps_capture_record_id() {
+        use axum::extract::Path;
+        use std::sync::Mutex;
+
+        // A provider that knows no records until one is created
+        let requests = Arc::new(Mutex::new(Vec::<String>::new()));
+        let created = Arc::new(Mutex::new(false));
+        let app = Router::new()
+            .route(
+                "/records",
+                get({
+                    let (requests, created) = (requests.clone(), created.clone());
+                    move || async move {
+                        requests.lock().unwrap().push("GET /records".to_string());
+                        let result = if *created.lock().unwrap() {
+                            serde_json::json!([{ "id": "rec1" }])
+                        } else {
+                            serde_json::json!([])
+                        };
+                        Json(serde_json::json!({ "result": result }))
+                    }
+                })
+                .post({
+                    let (requests, created) = (requests.clone(), created.clone());
+                    move || async move {
+                        requests.lock().unwrap().push("POST /records".to_string());
+                        *created.lock().unwrap() = true;
+                        Json(serde_json::json!({ "result": { "id": "rec1" } }))
+                    }
+                }),
+            )
+            .route(
+                "/records/:id",
+                axum::routing::put({
+                    let requests = requests.clone();
+                    move |Path(id): Path<String>, body: String| async move {
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("PUT /records/{} {}", id, body));
+                        "ok"
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut task = task(IpFamily::Ipv6);
+        task.webhook_method = "PUT".to_string();
+        task.webhook_url = format!("{}/records/{{{{ record_id }}}}", base);
+        task.webhook_body = Some("{{ combined_ip }}".to_string());
+        task.steps = serde_json::from_value(serde_json::json!([
+            {
+                "name": "find",
+                "webhook_url": format!("{}/records", base),
+                "webhook_method": "GET",
+                "captures": { "record_id": { "json_path": "result.0.id" } }
+            },
+            {
+                "name": "create",
+                "when": "not record_id",
+                "webhook_url": format!("{}/records", base),
+                "webhook_method": "POST",
+                "captures": { "record_id": { "regex": "\"id\":\"(\\w+)\"" } }
+            }
+        ]))
+        .unwrap();
+        assert!(task.validate_templates().is_ok());
+
+        let vars = TemplateVars::fixed(IpAddr::from_str("2001:db8::1").unwrap());
+        let clients = HttpClients::default();
+        send_webhook(&clients, &task, &vars).await.unwrap();
+        send_webhook(&clients, &task, &vars).await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "GET /records",
+                "POST /records",
+                "PUT /records/rec1 2001:db8::1",
+                "GET /records",
+                "PUT /records/rec1 2001:db8::1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_webhook_skips_steps() {
+        use std::sync::Mutex;
+
+        let requests = Arc::new(Mutex::new(Vec::<String>::new()));
+        let app = Router::new().fallback({
+            let requests = requests.clone();
+            move |request: Request| async move {
+                requests
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", request.method(), request.uri()));
+                "ok"
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut task = task(IpFamily::Ipv6);
+        task.steps = serde_json::from_value(serde_json::json!([{
+            "name": "find",
+            "webhook_url": format!("{}/records", base),
+            "webhook_method": "GET",
+        }]))
+        .unwrap();
+        task.success =
+            serde_json::from_value(serde_json::json!({ "body_contains": "created" })).unwrap();
+        let withdraw_task = task.withdraw_webhook(
+            format!("{}/records/home", base),
+            "DELETE".to_string(),
+            None,
+            HashMap::new(),
+        );
+
+        let vars = TemplateVars::fixed(IpAddr::from_str("2001:db8::1").unwrap());
+        send_webhook(&HttpClients::default(), &withdraw_task, &vars)
+            .await
+            .unwrap();
+        assert_eq!(*requests.lock().unwrap(), vec!["DELETE /records/home"]);
+    }
+
+    #[tokio::test]
+    async fn test_hung_endpoint_times_out() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let mut task = task(IpFamily::Ipv6);
+        task.webhook_url = format!("http://127.0.0.1:{}/", port);
+        task.http = Some(crate::config::HttpOptions {
+            timeout: Some(1),
+            ..Default::default()
+        });
+        let vars = TemplateVars::fixed(IpAddr::from_str("2001:db8::1").unwrap());
+        let error = send_webhook(&HttpClients::default(), &task, &vars)
+            .await
+            .unwrap_err();
+        assert!(crate::retry::is_retryable(
+            &crate::config::RetryPolicy::default(),
+            &error
+        ));
     }
 }
@@ -9,12 +9,14 @@ const App = {
       tasks: [],
       log_limit: 100,
       run_on_startup: true,
+      prefix_len: 64,
     },
     sse: null,
     reconnectTimer: null,
     currentTaskIndex: -1, // -1 for new task
     logCount: 0,
     isDirty: false,
+    retries: [],
   },
 
   // Defined Templates (Source of Truth)
@@ -49,6 +51,21 @@ const App = {
       ),
       suffix: "::1",
     },
+    cloudflare_api: {
+      name: "Cloudflare (API)",
+      webhook_method: "GET",
+      webhook_url: "",
+      webhook_headers: {},
+      webhook_body: null,
+      provider: {
+        type: "cloudflare",
+        api_token: "YOUR_TOKEN",
+        fqdn: "example.com",
+        ttl: 120,
+        proxied: false,
+      },
+      suffix: "::1",
+    },
     dynv6: {
       name: "Dynv6 (Zone)",
       webhook_method: "GET",
@@ -70,6 +87,20 @@ const App = {
       webhook_body: JSON.stringify({ data: "{{combined_ip}}" }, null, 2),
       suffix: "",
     },
+    dynv6_api: {
+      name: "Dynv6 (API)",
+      webhook_method: "GET",
+      webhook_url: "",
+      webhook_headers: {},
+      webhook_body: null,
+      provider: {
+        type: "dynv6",
+        token: "YOUR_TOKEN",
+        zone: "example.dynv6.net",
+        name: "sub.example.dynv6.net",
+      },
+      suffix: "",
+    },
     dynu: {
       name: "Dynu (Zone)",
       webhook_method: "GET",
@@ -115,6 +146,21 @@ const App = {
       webhook_body: null,
       suffix: "",
     },
+    desec_api: {
+      name: "deSEC.io (API)",
+      webhook_method: "GET",
+      webhook_url: "",
+      webhook_headers: {},
+      webhook_body: null,
+      provider: {
+        type: "desec",
+        token: "YOUR_TOKEN",
+        zone: "example.dedyn.io",
+        name: "example.dedyn.io",
+        ttl: 3600,
+      },
+      suffix: "",
+    },
     ydns: {
       name: "YDNS",
       webhook_method: "GET",
@@ -124,6 +170,42 @@ const App = {
       webhook_body: null,
       suffix: "",
     },
+    rfc2136: {
+      name: "RFC 2136 (nsupdate)",
+      webhook_method: "GET",
+      webhook_url: "",
+      webhook_headers: {},
+      webhook_body: null,
+      provider: {
+        type: "rfc2136",
+        server: "192.0.2.53",
+        zone: "example.com",
+        name: "home.example.com",
+        ttl: 300,
+        tsig: {
+          name: "ddns-key",
+          algorithm: "hmac-sha256",
+          secret: "YOUR_BASE64_SECRET",
+        },
+      },
+      suffix: "",
+    },
+    powerdns: {
+      name: "PowerDNS (API)",
+      webhook_method: "GET",
+      webhook_url: "",
+      webhook_headers: {},
+      webhook_body: null,
+      provider: {
+        type: "powerdns",
+        api_key: "YOUR_API_KEY",
+        zone: "example.com",
+        name: "home.example.com",
+        ttl: 300,
+        base_url: "http://127.0.0.1:8081",
+      },
+      suffix: "",
+    },
   },
 
   elements: {
@@ -139,15 +221,22 @@ const App = {
       id: document.getElementById("modal-task-id"),
       name: document.getElementById("modal-task-name"),
       suffix: document.getElementById("modal-task-suffix"),
+      suffixMode: document.getElementById("modal-task-suffix-mode"),
+      mac: document.getElementById("modal-task-mac"),
+      prefixLen: document.getElementById("modal-task-prefix-len"),
+      interfaces: document.getElementById("modal-task-interfaces"),
+      family: document.getElementById("modal-task-family"),
       method: document.getElementById("modal-task-method"),
       url: document.getElementById("modal-task-url"),
       headers: document.getElementById("modal-task-headers"),
       body: document.getElementById("modal-task-body"),
+      advanced: document.getElementById("modal-task-advanced"),
     },
 
     // Settings
     settingLogLimit: document.getElementById("setting-log-limit"),
     settingRunOnStartup: document.getElementById("setting-run-on-startup"),
+    settingPrefixLen: document.getElementById("setting-prefix-len"),
 
     // Logs
     logsOutput: document.getElementById("logs-output"),
@@ -197,6 +286,36 @@ const App = {
     this.connectSSE();
     this.fetchConfig();
     this.setupEventListeners();
+    this.fetchRetries();
+    setInterval(() => this.fetchRetries(), 5000);
+  },
+
+  async fetchRetries() {
+    try {
+      const response = await fetch("/api/retries");
+      if (!response.ok) return;
+      this.state.retries = await response.json();
+      this.renderRetryBadges();
+    } catch (error) {
+      console.error("Error fetching retries:", error);
+    }
+  },
+
+  renderRetryBadges() {
+    this.elements.taskList.querySelectorAll(".task-card").forEach((card) => {
+      const badge = card.querySelector(".task-retry-badge");
+      const retry = this.state.retries.find(
+        (r) => r.task_id === card.dataset.taskId,
+      );
+      if (!retry) {
+        badge.style.display = "none";
+        return;
+      }
+      const at = new Date(retry.next_attempt_at).toLocaleTimeString();
+      badge.textContent = `Retry ${retry.attempt + 1}/${retry.max_attempts} at ${at}`;
+      badge.title = `${retry.address}: ${retry.last_error}`;
+      badge.style.display = "inline-block";
+    });
   },
 
   initTemplates() {
@@ -269,6 +388,7 @@ const App = {
       this.elements.settingLogLimit.value = this.state.config.log_limit || 100;
       this.elements.settingRunOnStartup.checked =
         !!this.state.config.run_on_startup;
+      this.elements.settingPrefixLen.value = this.state.config.prefix_len || 64;
 
       // Settings change listeners
       this.elements.settingLogLimit.addEventListener("change", () =>
@@ -277,6 +397,9 @@ const App = {
       this.elements.settingRunOnStartup.addEventListener("change", () =>
         this.markDirty(),
       );
+      this.elements.settingPrefixLen.addEventListener("change", () =>
+        this.markDirty(),
+      );
 
       this.renderTasks();
       this.showToast("Configuration loaded", "success");
@@ -359,6 +482,7 @@ const App = {
     tasks.forEach((task, index) => {
       const clone = this.elements.taskTemplate.content.cloneNode(true);
       const card = clone.querySelector(".task-card");
+      card.dataset.taskId = task.id;
 
       // Name
       clone.querySelector(".task-name").textContent =
@@ -393,6 +517,7 @@ const App = {
 
       list.appendChild(clone);
     });
+    this.renderRetryBadges();
   },
 
   toggleTaskEnabled(index, isEnabled) {
@@ -432,10 +557,16 @@ const App = {
     const inputs = this.elements.modalInputs;
     inputs.name.value = task.name || "";
     inputs.suffix.value = task.suffix || "";
+    inputs.suffixMode.value = task.suffix_mode || "static";
+    inputs.mac.value = task.mac || "";
+    inputs.prefixLen.value = task.prefix_len || "";
+    inputs.interfaces.value = (task.interfaces || []).join(", ");
+    inputs.family.value = task.address_family || "ipv6";
     inputs.method.value = task.webhook_method || "GET";
     inputs.url.value = task.webhook_url || "";
     inputs.headers.value = this.objToString(task.webhook_headers);
     inputs.body.value = task.webhook_body || "";
+    inputs.advanced.value = this.advancedToString(task);
 
     // Show Modal
     this.elements.modal.style.display = "flex";
@@ -450,6 +581,9 @@ const App = {
       return;
     }
 
+    const advanced = this.parseAdvanced(inputs.advanced.value);
+    if (!advanced) return;
+
     // Get existing task to preserve ID and toggle states
     const existingTask =
       this.state.currentTaskIndex !== -1
@@ -457,9 +591,15 @@ const App = {
         : null;
 
     const taskData = {
+      ...advanced,
       id: existingTask ? existingTask.id : this.generateId(),
       name: inputs.name.value.trim(),
       suffix: inputs.suffix.value.trim(),
+      suffix_mode: inputs.suffixMode.value,
+      mac: inputs.mac.value.trim() || null,
+      prefix_len: this.parsePrefixLen(inputs.prefixLen.value),
+      interfaces: this.parseList(inputs.interfaces.value),
+      address_family: inputs.family.value,
       enabled: existingTask ? existingTask.enabled : true, // Preserve or Default
       allow_api_trigger: existingTask ? existingTask.allow_api_trigger : false, // Preserve or Default
       webhook_method: inputs.method.value,
@@ -525,6 +665,8 @@ const App = {
     );
     this.state.config.run_on_startup =
       this.elements.settingRunOnStartup.checked;
+    this.state.config.prefix_len =
+      this.parsePrefixLen(this.elements.settingPrefixLen.value) || 64;
 
     try {
       const response = await fetch("/api/config", {
@@ -544,7 +686,7 @@ const App = {
         throw new Error("Save failed: " + text);
       }
     } catch (error) {
-      this.showToast("Error saving configuration", "error");
+      this.showToast(error.message || "Error saving configuration", "error");
       console.error(error);
     }
   },
@@ -553,10 +695,19 @@ const App = {
     // Run test with current modal data
     const inputs = this.elements.modalInputs;
 
+    const advanced = this.parseAdvanced(inputs.advanced.value);
+    if (!advanced) return;
+
     const taskData = {
+      ...advanced,
       id: "test",
       name: inputs.name.value,
       suffix: inputs.suffix.value,
+      suffix_mode: inputs.suffixMode.value,
+      mac: inputs.mac.value.trim() || null,
+      prefix_len: this.parsePrefixLen(inputs.prefixLen.value),
+      interfaces: this.parseList(inputs.interfaces.value),
+      address_family: inputs.family.value,
       webhook_method: inputs.method.value,
       webhook_url: inputs.url.value,
       webhook_headers: this.stringToObj(inputs.headers.value),
@@ -567,7 +718,8 @@ const App = {
 
     const payload = {
       task: taskData,
-      fake_ip: "2001:db8::1", // Default test IP
+      // Default test IP
+      fake_ip: taskData.address_family === "ipv4" ? "192.0.2.1" : "2001:db8::1",
     };
 
     try {
@@ -592,6 +744,56 @@ const App = {
   },
 
   // Utilities
+  parsePrefixLen(value) {
+    const len = parseInt(value);
+    return len >= 1 && len <= 128 ? len : null;
+  },
+
+  // Task fields edited through dedicated modal inputs
+  modalFields: [
+    "id",
+    "name",
+    "suffix",
+    "suffix_mode",
+    "mac",
+    "prefix_len",
+    "interfaces",
+    "address_family",
+    "enabled",
+    "allow_api_trigger",
+    "webhook_method",
+    "webhook_url",
+    "webhook_headers",
+    "webhook_body",
+  ],
+
+  advancedToString(task) {
+    const extra = {};
+    Object.entries(task).forEach(([key, value]) => {
+      if (!this.modalFields.includes(key) && value !== null) extra[key] = value;
+    });
+    return Object.keys(extra).length ? JSON.stringify(extra, null, 2) : "";
+  },
+
+  parseAdvanced(str) {
+    if (!str.trim()) return {};
+    try {
+      const obj = JSON.parse(str);
+      if (obj && typeof obj === "object" && !Array.isArray(obj)) return obj;
+    } catch (e) {
+      // Fall through to the error below
+    }
+    this.showToast("Advanced options must be a JSON object", "error");
+    return null;
+  },
+
+  parseList(value) {
+    return value
+      .split(",")
+      .map((item) => item.trim())
+      .filter((item) => item);
+  },
+
   generateId() {
     return Math.random().toString(36).substr(2, 9);
   },